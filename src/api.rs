@@ -0,0 +1,53 @@
+// Telegram limits how fast a bot may send messages and the network to it fails
+// now and then: requests wait for their turn and are retried on failures.
+
+use std::future::Future;
+use std::time::Duration;
+
+use teloxide::adaptors::throttle::Throttle;
+use teloxide::requests::{Output, Request};
+use teloxide::RequestError;
+
+/// The bot every endpoint talks to Telegram with. It delays requests that would
+/// exceed the limits of Telegram, see
+/// <https://core.telegram.org/bots/faq#my-bot-is-hitting-limits-how-do-i-avoid-this>,
+/// and resends the ones still rejected with `RetryAfter` once Telegram allows to
+/// (`throttle::Settings::retry`).
+pub type Bot = Throttle<teloxide::Bot>;
+
+/// Attempts of a request failing with network errors, including the first one.
+const MAX_ATTEMPTS: u32 = 4;
+/// Delay before the second attempt, doubled before each next one.
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+
+pub trait RequestExt: Request<Err = RequestError> {
+    /// Sends the request, retrying it with backoff on network errors.
+    ///
+    /// A request that reached Telegram before the connection failed
+    /// is sent twice, which is better than not sent at all.
+    fn send_retrying(&self) -> impl Future<Output = Result<Output<Self>, RequestError>> + Send;
+}
+
+impl<R> RequestExt for R
+where
+    R: Request<Err = RequestError> + Sync,
+    Output<R>: Send,
+{
+    async fn send_retrying(&self) -> Result<Output<Self>, RequestError> {
+        let mut backoff = FIRST_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            let err = match self.send_ref().await {
+                Err(err) if attempt < MAX_ATTEMPTS => err,
+                result => return result,
+            };
+            if !matches!(err, RequestError::Network(_) | RequestError::Io(_)) {
+                return Err(err);
+            }
+            log::warn!("Attempt {attempt} of a request failed, retrying in {backoff:?}: {err}");
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
@@ -1,13 +1,519 @@
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
 use clap::*;
+use serde::Deserialize;
+use simplelog::LevelFilter;
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use url::Url;
+
+use crate::commands::endpoints::command_names;
+use crate::config::Config;
+use crate::settings::ChatSettings;
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// In-memory storage, periodically dumped as JSON files to `path` directory.
+    Json,
+    /// SQLite database in `path` file, every change is written immediately.
+    Sqlite,
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
-    /// Path of storage disk dump.
-    #[arg(short, long)]
+    /// Configuration file (e.g. `minasan.toml`), options given by the environment
+    /// or on the command line take precedence over it.
+    #[arg(short, long, env = "MINASAN_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Check the configuration and exit.
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Token of the bot.
+    #[arg(long, env = "TELOXIDE_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// File containing the token of the bot.
+    #[arg(long, env = "MINASAN_TOKEN_FILE")]
+    pub token_file: Option<PathBuf>,
+
+    /// Log level: `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    #[arg(long, env = "MINASAN_LOG_LEVEL", default_value = "info")]
+    pub log_level: LevelFilter,
+
+    /// Storage backend.
+    #[arg(short, long, env = "MINASAN_BACKEND", value_enum, default_value_t = Backend::Json)]
+    pub backend: Backend,
+
+    /// Path of storage disk dump (directory for `json`, database file for `sqlite`).
+    #[arg(short, long, env = "MINASAN_PATH")]
     pub path: Option<String>,
 
-    /// Time interval (seconds) of storage disk dump.
-    #[arg(short, long, default_value_t = 3600)]
-    pub interval: u16,
+    /// Create the storage directory (or the database's parent directory) if it does not exist.
+    #[arg(
+        long,
+        env = "MINASAN_CREATE_PATH",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        default_value_t = false,
+        default_missing_value = "true"
+    )]
+    pub create_path: bool,
+
+    /// Delay between a change and the storage disk dump (e.g. `30s`, `2h`), changes
+    /// made meanwhile are dumped together.
+    #[arg(
+        short,
+        long,
+        env = "MINASAN_INTERVAL",
+        default_value = "30s",
+        value_parser = parse_duration
+    )]
+    pub interval: Duration,
+
+    /// Time given to running handlers and the final dump on shutdown (e.g. `30s`).
+    #[arg(
+        long,
+        env = "MINASAN_SHUTDOWN_TIMEOUT",
+        default_value = "30s",
+        value_parser = parse_duration
+    )]
+    pub shutdown_timeout: Duration,
+
+    /// Bot API server to use instead of `https://api.telegram.org`,
+    /// e.g. a self-hosted `telegram-bot-api`. `TELOXIDE_API_URL` is used if unset.
+    #[arg(long, env = "MINASAN_API_URL")]
+    pub api_url: Option<Url>,
+
+    /// Receive updates with a webhook at this public URL instead of long polling.
+    #[arg(long, env = "MINASAN_WEBHOOK_URL")]
+    pub webhook_url: Option<Url>,
+
+    /// Address the webhook server listens on, usually behind a reverse proxy.
+    #[arg(long, env = "MINASAN_LISTEN", default_value = "0.0.0.0:8443")]
+    pub listen: SocketAddr,
+
+    /// Secret Telegram sends with every webhook request, generated on start if missing.
+    #[arg(
+        long,
+        env = "MINASAN_WEBHOOK_SECRET",
+        hide_env_values = true,
+        value_parser = parse_secret_token
+    )]
+    pub webhook_secret: Option<String>,
+
+    /// Chats to send detailed reports of failed commands to, e.g. the owners' user ids,
+    /// separated by commas.
+    #[arg(
+        long = "owner-chat-id",
+        env = "MINASAN_OWNER_CHAT_ID",
+        value_delimiter = ','
+    )]
+    pub owner_chat_ids: Vec<i64>,
+
+    /// Settings of the chats that did not choose otherwise, set by the configuration file.
+    #[arg(skip)]
+    pub chat: ChatSettings,
+}
+
+impl Args {
+    /// Parses the command line and the environment, taking the options they
+    /// leave unset from the configuration file. Exits on errors.
+    pub fn load() -> Self {
+        Self::try_load_from(std::env::args_os()).unwrap_or_else(|err| err.exit())
+    }
+
+    pub fn try_load_from<I, T>(args: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let mut command = Self::command();
+        let matches = command.try_get_matches_from_mut(args)?;
+        let mut args = Self::from_arg_matches(&matches)?;
+
+        let config = match &args.config {
+            Some(path) => Config::read(path).map_err(|err| command.error(ErrorKind::Io, err))?,
+            None => Config::default(),
+        };
+        let listen_given = given(&matches, "listen").is_some() || config.webhook.listen.is_some();
+        args.merge(&matches, config)
+            .map_err(|err| command.error(ErrorKind::ValueValidation, err))?;
+
+        let missing = if args.backend == Backend::Sqlite && args.path.is_none() {
+            Some("the `sqlite` backend requires `--path`")
+        } else if args.webhook_url.is_none() && (listen_given || args.webhook_secret.is_some()) {
+            Some("`--listen` and `--webhook-secret` require `--webhook-url`")
+        } else {
+            None
+        };
+        match missing {
+            Some(err) => Err(command.error(ErrorKind::MissingRequiredArgument, err)),
+            None => Ok(args),
+        }
+    }
+
+    /// Fills the options the command line and the environment left unset.
+    fn merge(&mut self, matches: &ArgMatches, config: Config) -> Result<(), String> {
+        match (given(matches, "token"), given(matches, "token_file")) {
+            (None, None) => {
+                if config.token.is_some() && config.token_file.is_some() {
+                    return Err("set either `token` or `token_file`".to_string());
+                }
+                self.token = config.token;
+                self.token_file = config.token_file;
+            }
+            // The command line takes precedence over the environment.
+            (Some(ValueSource::CommandLine), Some(ValueSource::EnvVariable)) => {
+                self.token_file = None
+            }
+            (Some(ValueSource::EnvVariable), Some(ValueSource::CommandLine)) => self.token = None,
+            (Some(_), Some(_)) => {
+                return Err("set either `--token` or `--token-file`".to_string());
+            }
+            _ => {}
+        }
+
+        let m = matches;
+        self.log_level = from_file(m, "log_level", config.log_level).unwrap_or(self.log_level);
+        self.backend = from_file(m, "backend", config.storage.backend).unwrap_or(self.backend);
+        self.path = from_file(m, "path", config.storage.path).or(self.path.take());
+        self.create_path =
+            from_file(m, "create_path", config.storage.create_path).unwrap_or(self.create_path);
+        self.interval = from_file(m, "interval", config.storage.interval).unwrap_or(self.interval);
+        self.shutdown_timeout = from_file(m, "shutdown_timeout", config.storage.shutdown_timeout)
+            .unwrap_or(self.shutdown_timeout);
+        self.api_url = from_file(m, "api_url", config.api_url).or(self.api_url.take());
+        if self.api_url.is_none() {
+            // The variable `teloxide::Bot::from_env` reads.
+            if let Some(url) = std::env::var_os("TELOXIDE_API_URL") {
+                let url = url.to_string_lossy();
+                let url = Url::parse(&url)
+                    .map_err(|err| format!("`TELOXIDE_API_URL` is not a URL: {err}"))?;
+                self.api_url = Some(url);
+            }
+        }
+        if let Some(owner_chat_ids) = from_file(m, "owner_chat_ids", config.owner_chat_ids) {
+            self.owner_chat_ids = owner_chat_ids;
+        }
+        self.webhook_url =
+            from_file(m, "webhook_url", config.webhook.url).or(self.webhook_url.take());
+        self.listen = from_file(m, "listen", config.webhook.listen).unwrap_or(self.listen);
+        self.webhook_secret =
+            from_file(m, "webhook_secret", config.webhook.secret).or(self.webhook_secret.take());
+
+        self.chat = ChatSettings::builtin();
+        if let Some(consent) = config.chat.consent {
+            self.chat.consent = consent;
+        }
+        if let Some(language) = config.chat.language {
+            self.chat.language = Some(language);
+        }
+        if let Some(cooldown) = config.chat.cooldown {
+            self.chat.cooldown = cooldown;
+        }
+        if let Some(delete_commands) = config.chat.delete_commands {
+            self.chat.delete_commands = delete_commands;
+        }
+        if let Some(commands) = config.chat.admin_commands {
+            self.chat.admin_commands = command_names(&commands.join(" "))
+                .map_err(|word| format!("unknown command `{word}` in `chat.admin_commands`"))?;
+        }
+        Ok(())
+    }
+
+    /// The token given directly or read from the token file.
+    pub fn read_token(&self) -> Result<String, String> {
+        match (&self.token, &self.token_file) {
+            (Some(token), _) => Ok(token.clone()),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .map(|token| token.trim().to_string())
+                .map_err(|err| format!("failed to read the token from {}: {err}", path.display())),
+            (None, None) => Err("no token, set `TELOXIDE_TOKEN`, `--token-file` \
+                or `token_file` in the configuration file"
+                .to_string()),
+        }
+    }
+}
+
+/// The value from the configuration file, unless the option was given otherwise.
+fn from_file<T>(matches: &ArgMatches, id: &str, value: Option<T>) -> Option<T> {
+    value.filter(|_| given(matches, id).is_none())
+}
+
+/// Where the option was given, unless it has its default value.
+fn given(matches: &ArgMatches, id: &str) -> Option<ValueSource> {
+    matches
+        .value_source(id)
+        .filter(|source| *source != ValueSource::DefaultValue)
+}
+
+/// Telegram accepts 1-256 characters `A-Z`, `a-z`, `0-9`, `_` and `-`.
+pub fn parse_secret_token(s: &str) -> Result<String, String> {
+    let valid = (1..=256).contains(&s.len())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err("must be 1-256 characters A-Z, a-z, 0-9, `_` or `-`".to_string())
+    }
+}
+
+/// Parses durations like `90s`, `1h 30m`, bare numbers are seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, humantime::DurationError> {
+    match s.trim().parse::<u64>() {
+        Ok(secs) => Ok(Duration::from_secs(secs)),
+        Err(_) => humantime::parse_duration(s),
+    }
+}
+
+// Here on only are the tests for command line parsing.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Held by every test that loads `Args`, as they share the environment.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Loads `Args` from the command line `args` with the variables `env` set.
+    fn load(args: &[&str], env: &[(&str, &str)]) -> Result<Args, Error> {
+        let _env = ENV
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        for (key, value) in env {
+            std::env::set_var(key, value);
+        }
+        let args = Args::try_load_from(["minasan"].iter().chain(args));
+        for (key, _) in env {
+            std::env::remove_var(key);
+        }
+        args
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("3600").unwrap(), Duration::from_secs(3600));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_duration("1m 30s").unwrap(), Duration::from_secs(90));
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("-5").is_err());
+    }
+
+    #[test]
+    fn test_parse_secret_token() {
+        assert!(parse_secret_token("s3cret_token-1").is_ok());
+        assert!(parse_secret_token("").is_err());
+        assert!(parse_secret_token("not so secret").is_err());
+        assert!(parse_secret_token(&"a".repeat(257)).is_err());
+    }
+
+    #[test]
+    fn test_webhook_args() {
+        let args = load(&["--listen", "127.0.0.1:80"], &[]);
+        assert!(args.is_err());
+
+        let args = load(
+            &[
+                "--webhook-url",
+                "https://example.com/minasan",
+                "--listen",
+                "127.0.0.1:8080",
+            ],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(args.webhook_url.unwrap().path(), "/minasan");
+        assert_eq!(args.listen, "127.0.0.1:8080".parse().unwrap());
+    }
+
+    #[test]
+    fn test_owner_chat_ids() {
+        let args = load(&["--owner-chat-id", "1,-100"], &[]).unwrap();
+        assert_eq!(args.owner_chat_ids, [1, -100]);
+
+        let args = load(&["--owner-chat-id", "1", "--owner-chat-id", "2"], &[]);
+        assert_eq!(args.unwrap().owner_chat_ids, [1, 2]);
+
+        assert!(load(&["--owner-chat-id", "1,me"], &[]).is_err());
+    }
+
+    #[test]
+    fn test_config_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("minasan.toml");
+        std::fs::write(
+            &config,
+            r#"
+            token = "from-file"
+
+            [storage]
+            backend = "sqlite"
+            path = "minasan.sqlite"
+            interval = "5m"
+
+            [webhook]
+            listen = "127.0.0.1:8080"
+
+            [chat]
+            admin_commands = ["kill", "/minasanadminonly"]
+            "#,
+        )
+        .unwrap();
+        let config = config.to_str().unwrap();
+
+        // The webhook options are only valid together.
+        assert!(load(&["--config", config], &[]).is_err());
+
+        let args = load(
+            &[
+                "--config",
+                config,
+                "--interval",
+                "10s",
+                "--webhook-url",
+                "https://example.com/minasan",
+                "--token-file",
+                "token",
+            ],
+            &[],
+        )
+        .unwrap();
+        assert_eq!(args.backend, Backend::Sqlite);
+        assert_eq!(args.path.as_deref(), Some("minasan.sqlite"));
+        assert_eq!(args.interval, Duration::from_secs(10));
+        assert_eq!(args.shutdown_timeout, Duration::from_secs(30));
+        assert_eq!(args.listen, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(args.token, None);
+        assert_eq!(args.token_file, Some(PathBuf::from("token")));
+        assert_eq!(
+            args.chat.admin_commands,
+            ["minasanadminonly", "minasankill"].map(String::from).into()
+        );
+
+        // Without a path the `sqlite` backend has nowhere to write.
+        let args = load(&["--backend", "sqlite"], &[]);
+        assert!(args.is_err());
+    }
+
+    #[test]
+    fn test_env_layer() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("minasan.toml");
+        std::fs::write(
+            &config,
+            r#"
+            [storage]
+            backend = "sqlite"
+            path = "file.sqlite"
+            create_path = true
+            interval = "5m"
+            shutdown_timeout = "1m"
+            "#,
+        )
+        .unwrap();
+        let config = config.to_str().unwrap();
+
+        // The file fills the defaults.
+        let args = load(&["--config", config], &[]).unwrap();
+        assert_eq!(args.backend, Backend::Sqlite);
+        assert_eq!(args.path.as_deref(), Some("file.sqlite"));
+        assert!(args.create_path);
+        assert_eq!(args.interval, Duration::from_secs(300));
+        assert_eq!(args.shutdown_timeout, Duration::from_secs(60));
+        assert_eq!(args.listen, "0.0.0.0:8443".parse().unwrap());
+
+        // The environment takes precedence over the file.
+        let env = [
+            ("MINASAN_PATH", "env.sqlite"),
+            ("MINASAN_CREATE_PATH", "false"),
+            ("MINASAN_INTERVAL", "1m"),
+            ("MINASAN_WEBHOOK_URL", "https://example.com/env"),
+            ("MINASAN_LISTEN", "127.0.0.1:8080"),
+        ];
+        let args = load(&["--config", config], &env).unwrap();
+        assert_eq!(args.backend, Backend::Sqlite);
+        assert_eq!(args.path.as_deref(), Some("env.sqlite"));
+        assert!(!args.create_path);
+        assert_eq!(args.interval, Duration::from_secs(60));
+        assert_eq!(args.shutdown_timeout, Duration::from_secs(60));
+        assert_eq!(args.webhook_url.unwrap().path(), "/env");
+        assert_eq!(args.listen, "127.0.0.1:8080".parse().unwrap());
+
+        // The command line takes precedence over the environment.
+        let args = load(
+            &[
+                "--config",
+                config,
+                "--path",
+                "cli.sqlite",
+                "--create-path",
+                "--backend",
+                "json",
+                "--shutdown-timeout",
+                "5s",
+            ],
+            &env,
+        )
+        .unwrap();
+        assert_eq!(args.backend, Backend::Json);
+        assert_eq!(args.path.as_deref(), Some("cli.sqlite"));
+        assert!(args.create_path);
+        assert_eq!(args.interval, Duration::from_secs(60));
+        assert_eq!(args.shutdown_timeout, Duration::from_secs(5));
+
+        let args = load(&["--config", config, "--create-path", "false"], &[]).unwrap();
+        assert!(!args.create_path);
+    }
+
+    #[test]
+    fn test_token_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("minasan.toml");
+        std::fs::write(&config, r#"token = "from-file""#).unwrap();
+        let config = config.to_str().unwrap();
+
+        let args = load(&["--config", config], &[]).unwrap();
+        assert_eq!(args.token.as_deref(), Some("from-file"));
+
+        // Either one given otherwise replaces both from the file.
+        let args = load(&["--config", config], &[("MINASAN_TOKEN_FILE", "env")]).unwrap();
+        assert_eq!(args.token, None);
+        assert_eq!(args.token_file, Some(PathBuf::from("env")));
+
+        // The command line takes precedence over the environment.
+        let args = load(&["--token-file", "cli"], &[("TELOXIDE_TOKEN", "env")]).unwrap();
+        assert_eq!(args.token, None);
+        assert_eq!(args.token_file, Some(PathBuf::from("cli")));
+        let args = load(&["--token", "cli"], &[("MINASAN_TOKEN_FILE", "env")]).unwrap();
+        assert_eq!(args.token.as_deref(), Some("cli"));
+        assert_eq!(args.token_file, None);
+
+        // Both from the same place are ambiguous.
+        assert!(load(&["--token", "cli", "--token-file", "cli"], &[]).is_err());
+        let env = [("TELOXIDE_TOKEN", "env"), ("MINASAN_TOKEN_FILE", "env")];
+        assert!(load(&[], &env).is_err());
+    }
+
+    #[test]
+    fn test_api_url() {
+        let env = [("TELOXIDE_API_URL", "http://localhost:8081")];
+        let args = load(&[], &env).unwrap();
+        assert_eq!(args.api_url.unwrap().as_str(), "http://localhost:8081/");
+
+        let args = load(&["--api-url", "http://localhost:8082"], &env).unwrap();
+        assert_eq!(args.api_url.unwrap().as_str(), "http://localhost:8082/");
+
+        assert!(load(&[], &[("TELOXIDE_API_URL", "localhost")]).is_err());
+        assert_eq!(load(&[], &[]).unwrap().api_url, None);
+    }
 }
@@ -1,64 +1,253 @@
-// Poll2ChatId is really stupid, need to change it in the nearest future
+// Commands of the bot and the endpoints handling every kind of update.
 
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
 use std::sync::Arc;
+use std::time::Duration;
 
 use teloxide::prelude::*;
 use teloxide::utils::command::BotCommands;
-use teloxide::RequestError;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
 
-use crate::storage::ChatStorage;
+use crate::api::{Bot, RequestExt};
+use crate::storage::Storage;
 
+/// Endpoints fail on both Telegram and storage errors.
+pub type HandlerResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+/// Descriptions shown by `/minasanhelp` are the `help-<name>` messages of `i18n`.
 #[derive(BotCommands, Debug, PartialEq, Clone)]
 #[command(rename_rule = "lowercase")]
 pub enum Command {
     /// Displays commands description.
-    #[command(description = "Displays commands description.")]
     MinasanHelp,
-    /// Tag everyone.
-    #[command(description = "Tags every chat member consented to be tagged.")]
-    Minasan,
+    /// Tag everyone, optionally with an announcement text.
+    Minasan(String),
     /// Stops the bot and removes it from the chat.
-    #[command(description = "Deletes the last active poll and removes the bot from the group.")]
     MinasanKill,
     /// Resend currently active poll to this chat.
-    #[command(description = "Shows the last active poll.")]
     MinasanPoll,
     /// Bot creates poll and starts tracking users.
-    #[command(description = "Activates the bot and starts poll.")]
     MinasanStart,
     /// Restarts the bot, recreating the poll.
-    #[command(description = "Recreates the poll.")]
     MinasanRestart,
+    /// Lists tag groups of this chat.
+    MinasanGroups,
+    /// Creates a named tag group.
+    MinasanNewGroup(String),
+    /// Deletes a named tag group.
+    MinasanDelGroup(String),
+    /// Opts the sender in to being tagged, or to a tag group.
+    MinasanJoin(String),
+    /// Opts the sender out of being tagged, or out of a tag group.
+    MinasanLeave(String),
+    /// Shows or sets how members consent to be tagged.
+    MinasanMode(String),
+    /// Shows or sets the commands only administrators may run.
+    MinasanAdminOnly(String),
+    /// Shows the settings menu of this chat.
+    MinasanSettings,
+}
+
+impl Command {
+    /// Name the command is invoked by, without `/`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::MinasanHelp => "minasanhelp",
+            Self::Minasan(_) => "minasan",
+            Self::MinasanKill => "minasankill",
+            Self::MinasanPoll => "minasanpoll",
+            Self::MinasanStart => "minasanstart",
+            Self::MinasanRestart => "minasanrestart",
+            Self::MinasanGroups => "minasangroups",
+            Self::MinasanNewGroup(_) => "minasannewgroup",
+            Self::MinasanDelGroup(_) => "minasandelgroup",
+            Self::MinasanJoin(_) => "minasanjoin",
+            Self::MinasanLeave(_) => "minasanleave",
+            Self::MinasanMode(_) => "minasanmode",
+            Self::MinasanAdminOnly(_) => "minasanadminonly",
+            Self::MinasanSettings => "minasansettings",
+        }
+    }
+}
+
+/// Poll answers are kept for at most this many polls the storage does not know.
+const MAX_PENDING_POLLS: usize = 1000;
+
+/// Answers to polls the storage lost track of, kept in memory until
+/// the chat of the poll is found again.
+#[derive(Default)]
+pub struct PendingVotes {
+    answers: Mutex<HashMap<String, Vec<PollAnswer>>>,
+    /// Chats told that their poll was lost.
+    warned: Mutex<HashSet<ChatId>>,
+}
+
+impl PendingVotes {
+    /// Returns `false` if there are too many unknown polls to keep the answer.
+    pub async fn push(&self, answer: PollAnswer) -> bool {
+        let mut answers = self.answers.lock().await;
+        if answers.len() >= MAX_PENDING_POLLS && !answers.contains_key(&answer.poll_id) {
+            return false;
+        }
+        answers
+            .entry(answer.poll_id.clone())
+            .or_default()
+            .push(answer);
+        true
+    }
+
+    /// Answers to the poll in the order they came.
+    pub async fn take(&self, poll_id: &str) -> Vec<PollAnswer> {
+        self.answers
+            .lock()
+            .await
+            .remove(poll_id)
+            .unwrap_or_default()
+    }
+
+    /// Returns `true` only the first time it is called for the chat.
+    async fn try_warn(&self, chat_id: ChatId) -> bool {
+        self.warned.lock().await.insert(chat_id)
+    }
+}
+
+/// When everyone was last tagged in each chat with a cooldown.
+#[derive(Default)]
+pub struct Cooldowns {
+    last_tags: Mutex<HashMap<ChatId, Instant>>,
+}
+
+impl Cooldowns {
+    /// Records a tag unless the previous one was less than `cooldown` ago,
+    /// returning the time left until the next one then.
+    pub async fn try_tag(&self, chat_id: ChatId, cooldown: Duration) -> Result<(), Duration> {
+        if cooldown.is_zero() {
+            return Ok(());
+        }
+        let mut last_tags = self.last_tags.lock().await;
+        let now = Instant::now();
+        if let Some(elapsed) = last_tags.get(&chat_id).map(|last| now - *last) {
+            if elapsed < cooldown {
+                return Err(cooldown - elapsed);
+            }
+        }
+        last_tags.insert(chat_id, now);
+        Ok(())
+    }
+
+    /// Forgets the tag `try_tag` recorded last, once it failed to be sent.
+    /// The one before was at least a cooldown ago, so it does not matter.
+    pub async fn release(&self, chat_id: ChatId) {
+        self.last_tags.lock().await.remove(&chat_id);
+    }
 }
 
 pub mod endpoints {
-    use teloxide::payloads::SendPoll;
-    use teloxide::requests::JsonRequest;
-    use teloxide::types::{MediaKind, Message, MessageId, MessageKind};
+    use std::collections::BTreeSet;
+    use teloxide::types::{
+        Chat, InlineKeyboardButton, InlineKeyboardMarkup, Me, MediaKind, Message, MessageId,
+        MessageKind, User,
+    };
+    use teloxide::{ApiError, RequestError};
+
+    use crate::settings::{ChatSettings, ConsentMode, Language};
+    use crate::{i18n, mentions};
 
     use super::*;
 
-    const POLL_OPTIONS: [&str; 2] = ["I do.", "I don't."];
+    /// Callback data of the consent buttons.
+    const TAG_ME: &str = "tag_me";
+    const DONT_TAG_ME: &str = "dont_tag_me";
+    /// How long confirmations of `reply_briefly` stay in the chat.
+    const BRIEF_REPLY_TTL: Duration = Duration::from_secs(15);
+    /// Prefix of the callback data of the settings menu buttons.
+    const SETTINGS: &str = "settings:";
+    /// Cooldowns the settings menu goes through, in seconds.
+    const COOLDOWNS: [u64; 5] = [0, 60, 300, 900, 3600];
+    /// Question of the polls made before the bot spoke several languages.
+    const LEGACY_CONSENT_QUESTION: &str =
+        "Do you consent to be tagged by `minasan` bot, via submission of your @username?";
+
+    /// Language to answer the message in, see `choose_language`.
+    pub async fn message_language(message: Message, chat_storage: Arc<dyn Storage>) -> Language {
+        choose_language(&chat_storage, message.chat.id, message.from()).await
+    }
+
+    pub async fn query_language(query: CallbackQuery, chat_storage: Arc<dyn Storage>) -> Language {
+        match &query.message {
+            Some(message) => {
+                choose_language(&chat_storage, message.chat.id, Some(&query.from)).await
+            }
+            None => user_language(&query.from).unwrap_or_default(),
+        }
+    }
+
+    pub async fn member_language(
+        update: ChatMemberUpdated,
+        chat_storage: Arc<dyn Storage>,
+    ) -> Language {
+        choose_language(&chat_storage, update.chat.id, Some(&update.from)).await
+    }
+
+    /// The language the chat chose, otherwise the one of the user's Telegram
+    /// client, otherwise English.
+    async fn choose_language(
+        chat_storage: &Arc<dyn Storage>,
+        chat_id: ChatId,
+        user: Option<&User>,
+    ) -> Language {
+        let chosen = match chat_storage.get_settings(chat_id).await {
+            Ok(settings) => settings.unwrap_or_else(|| chat_storage.defaults()).language,
+            Err(err) => {
+                log::error!("Failed to get settings of chat # {chat_id}: {err}");
+                chat_storage.defaults().language
+            }
+        };
+        chosen
+            .or_else(|| user.and_then(user_language))
+            .unwrap_or_default()
+    }
+
+    pub fn user_language(user: &User) -> Option<Language> {
+        user.language_code.as_deref().and_then(Language::from_code)
+    }
 
     pub async fn start(
         bot: Bot,
+        me: Me,
         message: Message,
-        chat_storage: Arc<ChatStorage>,
-    ) -> Result<(), RequestError> {
+        chat_storage: Arc<dyn Storage>,
+        pending_votes: Arc<PendingVotes>,
+        language: Language,
+    ) -> HandlerResult {
         let chat_id = message.chat.id;
 
-        if chat_storage.get_message_id(chat_id).await.is_some() {
-            bot.send_message(
-                chat_id,
-                "\
-                You have already started the poll, if you want to restart, \
-                use the `/minasanrestart` command.\
-            ",
-            )
-            .await?;
+        // The storage may have lost the chat while its poll is still there.
+        let lost_poll = message
+            .reply_to_message()
+            .filter(|reply| reply.from().is_some_and(|user| user.id == me.id))
+            .and_then(|reply| Some((reply.id, reply.poll()?)))
+            .filter(|(_, poll)| is_consent_question(&poll.question));
+
+        if chat_storage.get_message_id(chat_id).await?.is_some() {
+            bot.send_message(chat_id, i18n::text(language, "already-started"))
+                .send_retrying()
+                .await?;
+        } else if let Some((message_id, poll)) = lost_poll {
+            chat_storage
+                .add_chat(chat_id, message_id, ConsentMode::Poll)
+                .await?;
+            register_poll(&chat_storage, &pending_votes, chat_id, poll.id.clone()).await?;
+            bot.send_message(chat_id, i18n::text(language, "poll-recovered"))
+                .reply_to_message_id(message_id)
+                .send_retrying()
+                .await?;
         } else {
-            let message_id = create_poll(bot, chat_id, Arc::clone(&chat_storage)).await?;
-            chat_storage.add_chat(chat_id, message_id).await;
+            let (message_id, consent) =
+                create_consent(bot, chat_id, Arc::clone(&chat_storage), language).await?;
+            chat_storage.add_chat(chat_id, message_id, consent).await?;
         }
         Ok(())
     }
@@ -66,21 +255,19 @@ pub mod endpoints {
     pub async fn restart(
         bot: Bot,
         message: Message,
-        chat_storage: Arc<ChatStorage>,
-    ) -> Result<(), RequestError> {
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
         let chat_id = message.chat.id;
 
-        if let Some(message_id) = chat_storage.get_message_id(chat_id).await {
-            bot.delete_message(chat_id, message_id).await?;
-            chat_storage.clean_users(chat_id).await;
-            create_poll(bot, chat_id, chat_storage).await?;
+        if let Some(message_id) = chat_storage.get_message_id(chat_id).await? {
+            delete_poll(&bot, chat_id, message_id).await?;
+            chat_storage.clean_users(chat_id).await?;
+            create_consent(bot, chat_id, chat_storage, language).await?;
         } else {
-            bot.send_message(
-                chat_id,
-                "You haven't started working with me. \
-            Please use `/minasanstart` command.",
-            )
-            .await?;
+            bot.send_message(chat_id, i18n::text(language, "not-started"))
+                .send_retrying()
+                .await?;
         }
         Ok(())
     }
@@ -88,88 +275,444 @@ pub mod endpoints {
     pub async fn kill(
         bot: Bot,
         message: Message,
-        chat_storage: Arc<ChatStorage>,
-    ) -> Result<(), RequestError> {
-        let poll_message_id = chat_storage.get_message_id(message.chat.id).await;
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
+        let poll_message_id = chat_storage.get_message_id(message.chat.id).await?;
 
         if let Some(poll_message_id) = poll_message_id {
-            bot.delete_message(message.chat.id, poll_message_id).await?;
-            chat_storage.remove_chat(message.chat.id).await;
+            delete_poll(&bot, message.chat.id, poll_message_id).await?;
+            chat_storage.remove_chat(message.chat.id).await?;
         }
-        bot.send_message(message.chat.id, "I will work here no more!")
+        bot.send_message(message.chat.id, i18n::text(language, "goodbye"))
+            .send_retrying()
             .await?;
-        bot.leave_chat(message.chat.id).await?;
+        bot.leave_chat(message.chat.id).send_retrying().await?;
         Ok(())
     }
 
     pub async fn tag_everyone(
         bot: Bot,
         message: Message,
-        chat_storage: Arc<ChatStorage>,
-    ) -> Result<(), RequestError> {
+        text: String,
+        chat_storage: Arc<dyn Storage>,
+        cooldowns: Arc<Cooldowns>,
+        language: Language,
+    ) -> HandlerResult {
         let chat_id = message.chat.id;
-        if let Some(users) = chat_storage.get_users(chat_id).await {
-            let message = if users.is_empty() {
-                String::from("No user provided any @username!!!")
-            } else {
-                users
-                    .into_iter()
-                    .map(|s| format!("@{s}"))
-                    .collect::<Vec<String>>()
-                    .join(" ")
+        if let Some(users) = chat_storage.get_users(chat_id).await? {
+            // `/minasan #<group> [text]` tags only the members of that group,
+            // otherwise all the text is announced to everyone.
+            let (users, usernames, text) = match text.trim().strip_prefix('#') {
+                Some(tagged) => {
+                    let (group, rest) = tagged
+                        .split_once(char::is_whitespace)
+                        .unwrap_or((tagged, ""));
+                    let Some(group) = group_name(group) else {
+                        let text = i18n::text(language, "invalid-group");
+                        return reply_briefly(&bot, &message, text).await;
+                    };
+                    match chat_storage.get_group(chat_id, &group).await? {
+                        Some(members) => (members, Vec::new(), rest),
+                        None => {
+                            let text = i18n::format(language, "no-group", &[("group", &group)]);
+                            return reply_briefly(&bot, &message, text).await;
+                        }
+                    }
+                }
+                None => {
+                    let usernames = chat_storage.get_usernames(chat_id).await?;
+                    (users, usernames, text.as_str())
+                }
             };
 
-            bot.send_message(chat_id, message).await?;
+            if users.is_empty() && usernames.is_empty() {
+                bot.send_message(chat_id, i18n::text(language, "nobody-consented"))
+                    .send_retrying()
+                    .await?;
+            } else {
+                let settings = chat_storage
+                    .get_settings(chat_id)
+                    .await?
+                    .unwrap_or_else(|| chat_storage.defaults());
+                if let Err(left) = cooldowns.try_tag(chat_id, settings.cooldown).await {
+                    let left = Duration::from_secs(left.as_secs_f64().ceil() as u64);
+                    let time = humantime::format_duration(left);
+                    let text = i18n::format(language, "cooldown", &[("time", &time)]);
+                    return reply_briefly(&bot, &message, text).await;
+                }
+                let chunks = mentions::split(
+                    text,
+                    users,
+                    usernames,
+                    language,
+                    mentions::MAX_MESSAGE_LENGTH,
+                    mentions::MAX_MENTIONS,
+                );
+                let reply_to = message.reply_to_message().map(|m| m.id);
+                let result = send_chunks(&bot, chat_id, reply_to, chunks, language).await;
+                if result.is_err() {
+                    cooldowns.release(chat_id).await;
+                }
+                result?;
+            }
         } else {
-            bot.send_message(
-                chat_id,
-                "You haven't started the poll, \
-            please use `/minasanstart` command",
-            )
-            .await?;
+            bot.send_message(chat_id, i18n::text(language, "not-started"))
+                .send_retrying()
+                .await?;
         }
         Ok(())
     }
 
     pub async fn update_users(
         _bot: Bot,
-        chat_storage: Arc<ChatStorage>,
+        chat_storage: Arc<dyn Storage>,
+        pending_votes: Arc<PendingVotes>,
         poll_answer: PollAnswer,
-    ) -> Result<(), RequestError> {
-        let Some(chat_id) = chat_storage.poll2chat(&poll_answer.poll_id).await else {
-            log::warn!(
-                "Trying to update users for unstored chat, \
-            bot was likely restarted there."
-            );
+    ) -> HandlerResult {
+        let Some(chat_id) = chat_storage.poll2chat(&poll_answer.poll_id).await? else {
+            // Counted once the poll is found again, see `start`.
+            let poll_id = poll_answer.poll_id.clone();
+            if pending_votes.push(poll_answer).await {
+                log::info!("Keeping an answer to unknown poll {poll_id} until its chat is found.");
+            } else {
+                log::warn!("Dropping an answer to unknown poll {poll_id}, too many unknown polls.");
+            }
             return Ok(());
         };
+        apply_answer(&chat_storage, chat_id, poll_answer).await
+    }
 
+    async fn apply_answer(
+        chat_storage: &Arc<dyn Storage>,
+        chat_id: ChatId,
+        poll_answer: PollAnswer,
+    ) -> HandlerResult {
+        let user = poll_answer.user;
+        forget_username(chat_storage, chat_id, &user).await?;
         if let Some(v) = poll_answer.option_ids.first() {
             match v {
                 0 => {
-                    if chat_storage.get_message_id(chat_id).await.is_some() {
+                    if chat_storage.get_message_id(chat_id).await?.is_some() {
                         chat_storage
-                            .add_user(chat_id, poll_answer.user.username.unwrap())
-                            .await;
+                            .add_user(chat_id, user.id, user.full_name())
+                            .await?;
                     }
                 },
                 1 => {},
                 x => log::error!("Invalid poll option {x} in chat # {chat_id}, check what the fuck has happened!"),
             }
         } else {
-            chat_storage
-                .remove_user(chat_id, poll_answer.user.username.unwrap())
-                .await;
+            chat_storage.remove_user(chat_id, user.id).await?;
         };
         Ok(())
     }
 
-    pub async fn help(
+    /// Stops tagging the @username a member consented with before users
+    /// were tracked by id, their own choice replaces it.
+    async fn forget_username(
+        chat_storage: &Arc<dyn Storage>,
+        chat_id: ChatId,
+        user: &User,
+    ) -> HandlerResult {
+        if let Some(username) = &user.username {
+            chat_storage.remove_username(chat_id, username).await?;
+        }
+        Ok(())
+    }
+
+    /// Asks to restart the poll of a chat the storage lost track of, which
+    /// happens to dumps older than poll ids, as answers to it cannot be
+    /// counted. Told once per chat, on the first command other than a restart.
+    pub async fn warn_lost_poll(
         bot: Bot,
         message: Message,
-        _chat_storage: Arc<ChatStorage>,
-    ) -> Result<(), RequestError> {
-        bot.send_message(message.chat.id, Command::descriptions().to_string())
+        command: Command,
+        chat_storage: Arc<dyn Storage>,
+        pending_votes: Arc<PendingVotes>,
+        language: Language,
+    ) {
+        let chat_id = message.chat.id;
+        if matches!(command, Command::MinasanRestart | Command::MinasanKill) {
+            return;
+        }
+        let result = try_warn_lost_poll(&bot, chat_id, &chat_storage, &pending_votes, language);
+        if let Err(err) = result.await {
+            log::warn!("Failed to warn chat # {chat_id} about its lost poll: {err}");
+        }
+    }
+
+    async fn try_warn_lost_poll(
+        bot: &Bot,
+        chat_id: ChatId,
+        chat_storage: &Arc<dyn Storage>,
+        pending_votes: &Arc<PendingVotes>,
+        language: Language,
+    ) -> HandlerResult {
+        let Some(message_id) = chat_storage.get_message_id(chat_id).await? else {
+            return Ok(());
+        };
+        // Buttons have no poll to lose, even once the chat chose a poll for its restart.
+        if chat_storage.get_consent(chat_id).await? != Some(ConsentMode::Poll)
+            || chat_storage.get_poll(chat_id).await?.is_some()
+            || !pending_votes.try_warn(chat_id).await
+        {
+            return Ok(());
+        }
+
+        log::info!("Lost the poll of chat # {chat_id}, asking to restart it.");
+        bot.send_message(chat_id, i18n::text(language, "poll-lost"))
+            .reply_to_message_id(message_id)
+            .allow_sending_without_reply(true)
+            .send_retrying()
+            .await?;
+        Ok(())
+    }
+
+    /// Makes the poll the active one of the chat and counts its pending answers.
+    async fn register_poll(
+        chat_storage: &Arc<dyn Storage>,
+        pending_votes: &Arc<PendingVotes>,
+        chat_id: ChatId,
+        poll_id: String,
+    ) -> HandlerResult {
+        chat_storage.update_poll(chat_id, poll_id.clone()).await?;
+        let answers = pending_votes.take(&poll_id).await;
+        log::info!(
+            "Found poll {poll_id} of chat # {chat_id}, counting {} pending answer(s).",
+            answers.len()
+        );
+        for answer in answers {
+            apply_answer(chat_storage, chat_id, answer).await?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the chat once the bot is removed from it,
+    /// and introduces itself when added to one.
+    pub async fn update_membership(
+        bot: Bot,
+        update: ChatMemberUpdated,
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
+        let chat_id = update.chat.id;
+
+        let was_present = update.old_chat_member.is_present();
+        let is_present = update.new_chat_member.is_present();
+
+        if was_present && !is_present {
+            if chat_storage.remove_chat(chat_id).await?.is_some() {
+                log::info!("Removed from chat # {chat_id}, forgot its members.");
+            }
+        } else if !was_present && is_present {
+            let greeting = if chat_storage.get_message_id(chat_id).await?.is_some() {
+                "greeting-again"
+            } else {
+                "greeting"
+            };
+            bot.send_message(chat_id, i18n::text(language, greeting))
+                .send_retrying()
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Stops tagging members once they leave the chat. Telegram sends
+    /// `chat_member` updates only to administrator bots, so the service
+    /// message about a member leaving is handled too.
+    pub async fn member_left(
+        update: ChatMemberUpdated,
+        chat_storage: Arc<dyn Storage>,
+    ) -> HandlerResult {
+        if update.old_chat_member.is_present() && !update.new_chat_member.is_present() {
+            forget_member(&chat_storage, update.chat.id, &update.new_chat_member.user).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn left_chat_member(
+        message: Message,
+        user: User,
+        chat_storage: Arc<dyn Storage>,
+    ) -> HandlerResult {
+        forget_member(&chat_storage, message.chat.id, &user).await
+    }
+
+    async fn forget_member(
+        chat_storage: &Arc<dyn Storage>,
+        chat_id: ChatId,
+        user: &User,
+    ) -> HandlerResult {
+        forget_username(chat_storage, chat_id, user).await?;
+        if chat_storage.forget_user(chat_id, user.id).await? == Some(true) {
+            log::info!("User # {} left chat # {chat_id}, forgot them.", user.id);
+        }
+        Ok(())
+    }
+
+    /// Follows a group upgraded to a supergroup. Telegram tells about it both
+    /// in the old chat and in the new one, whichever comes first moves the chat.
+    pub async fn migrate(message: Message, chat_storage: Arc<dyn Storage>) -> HandlerResult {
+        let (from, to) = match (message.migrate_to_chat_id(), message.migrate_from_chat_id()) {
+            (Some(to), _) => (message.chat.id, to),
+            (_, Some(from)) => (from, message.chat.id),
+            (None, None) => return Ok(()),
+        };
+        if chat_storage.migrate_chat(from, to).await?.is_some() {
+            log::info!("Chat # {from} was upgraded to # {to}, moved its data.");
+        }
+        Ok(())
+    }
+
+    /// Tracks presses of the consent buttons, keeping the counter
+    /// of consented members in their message up to date.
+    pub async fn update_consent(
+        bot: Bot,
+        query: CallbackQuery,
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
+        let Some(message) = &query.message else {
+            bot.answer_callback_query(query.id).send_retrying().await?;
+            return Ok(());
+        };
+        let chat_id = message.chat.id;
+
+        // Buttons of a deleted or replaced message may still be pressed.
+        if chat_storage.get_message_id(chat_id).await? != Some(message.id) {
+            bot.answer_callback_query(query.id)
+                .text(i18n::text(language, "poll-inactive"))
+                .send_retrying()
+                .await?;
+            return Ok(());
+        }
+
+        let user = &query.from;
+        let answer = match query.data.as_deref() {
+            Some(TAG_ME) => {
+                chat_storage
+                    .add_user(chat_id, user.id, user.full_name())
+                    .await?;
+                "will-be-tagged"
+            }
+            Some(DONT_TAG_ME) => {
+                chat_storage.remove_user(chat_id, user.id).await?;
+                "will-not-be-tagged"
+            }
+            data => {
+                log::warn!("Unknown callback data {data:?} in chat # {chat_id}");
+                bot.answer_callback_query(query.id).send_retrying().await?;
+                return Ok(());
+            }
+        };
+        forget_username(&chat_storage, chat_id, user).await?;
+        update_counter(&bot, chat_id, message.id, &chat_storage, language).await?;
+        bot.answer_callback_query(query.id)
+            .text(i18n::text(language, answer))
+            .send_retrying()
+            .await?;
+        Ok(())
+    }
+
+    /// Edits the number of consented members into the consent buttons message.
+    async fn update_counter(
+        bot: &Bot,
+        chat_id: ChatId,
+        message_id: MessageId,
+        chat_storage: &Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
+        let consented = chat_storage
+            .get_users(chat_id)
+            .await?
+            .unwrap_or_default()
+            .len();
+        let result = bot
+            .edit_message_text(chat_id, message_id, consent_text(consented, language))
+            .reply_markup(consent_keyboard(language))
+            .send_retrying()
+            .await;
+        match result {
+            // The counter did not change or was already updated by a concurrent press.
+            Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => {}
+            Err(err) => log::warn!("Failed to update the counter in chat # {chat_id}: {err}"),
+        }
+        Ok(())
+    }
+
+    /// Keeps the counter up to date when consent is given with a command.
+    async fn update_counter_if_buttons(
+        bot: &Bot,
+        chat_id: ChatId,
+        chat_storage: &Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
+        let consent = chat_storage.get_consent(chat_id).await?;
+        let message_id = chat_storage.get_message_id(chat_id).await?;
+        if let (Some(ConsentMode::Buttons), Some(message_id)) = (consent, message_id) {
+            update_counter(bot, chat_id, message_id, chat_storage, language).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn consent_mode(
+        bot: Bot,
+        message: Message,
+        mode: String,
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
+        let chat_id = message.chat.id;
+
+        let mode = match mode.trim().to_lowercase().as_str() {
+            "" => None,
+            "poll" => Some(ConsentMode::Poll),
+            "buttons" => Some(ConsentMode::Buttons),
+            _ => {
+                bot.send_message(chat_id, i18n::text(language, "invalid-mode"))
+                    .send_retrying()
+                    .await?;
+                return Ok(());
+            }
+        };
+        let Some(mut settings) = chat_storage.get_settings(chat_id).await? else {
+            bot.send_message(chat_id, i18n::text(language, "not-started"))
+                .send_retrying()
+                .await?;
+            return Ok(());
+        };
+
+        let text = match mode {
+            None => match settings.consent {
+                ConsentMode::Poll => "mode-poll",
+                ConsentMode::Buttons => "mode-buttons",
+            },
+            Some(mode) if mode == settings.consent => "mode-unchanged",
+            Some(mode) => {
+                settings.consent = mode;
+                chat_storage.update_settings(chat_id, settings).await?;
+                "mode-changed"
+            }
+        };
+        bot.send_message(chat_id, i18n::text(language, text))
+            .send_retrying()
+            .await?;
+        Ok(())
+    }
+
+    pub async fn help(bot: Bot, message: Message, language: Language) -> HandlerResult {
+        let text = Command::bot_commands()
+            .into_iter()
+            .map(|command| {
+                let name = command.command.trim_start_matches('/');
+                let description = i18n::text(language, &format!("help-{name}"));
+                format!("/{name} — {description}")
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        bot.send_message(message.chat.id, text)
+            .send_retrying()
             .await?;
         Ok(())
     }
@@ -177,40 +720,807 @@ pub mod endpoints {
     pub async fn get_poll(
         bot: Bot,
         message: Message,
-        chat_storage: Arc<ChatStorage>,
-    ) -> Result<(), RequestError> {
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
         let chat_id = message.chat.id;
 
-        if let Some(message_id) = chat_storage.get_message_id(chat_id).await {
-            bot.send_message(chat_id, "Here's your poll.").await?;
-            bot.forward_message(chat_id, chat_id, message_id).await?;
+        if let Some(message_id) = chat_storage.get_message_id(chat_id).await? {
+            // The message the chat has, whatever it chose for its next restart.
+            let consent = chat_storage.get_consent(chat_id).await?.unwrap_or_default();
+            match consent {
+                ConsentMode::Poll => {
+                    bot.send_message(chat_id, i18n::text(language, "heres-your-poll"))
+                        .send_retrying()
+                        .await?;
+                    bot.forward_message(chat_id, chat_id, message_id)
+                        .send_retrying()
+                        .await?;
+                }
+                // Forwarded messages lose their buttons.
+                ConsentMode::Buttons => {
+                    bot.send_message(chat_id, i18n::text(language, "heres-your-poll"))
+                        .reply_to_message_id(message_id)
+                        .send_retrying()
+                        .await?;
+                }
+            }
         } else {
-            bot.send_message(
-                chat_id,
-                "You haven't started any poll.\
-                Please, use the `/minasanstart` command.",
-            )
+            bot.send_message(chat_id, i18n::text(language, "not-started"))
+                .send_retrying()
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn list_groups(
+        bot: Bot,
+        message: Message,
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
+        let chat_id = message.chat.id;
+
+        let text = match chat_storage.get_groups(chat_id).await? {
+            None => i18n::text(language, "not-started"),
+            Some(groups) if groups.is_empty() => i18n::text(language, "no-groups"),
+            Some(groups) => {
+                let mut groups = groups
+                    .into_iter()
+                    .map(|(group, members)| {
+                        let count = members.len();
+                        i18n::format(
+                            language,
+                            "group-members",
+                            &[("group", &group), ("count", &count)],
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                groups.sort();
+                groups.join("\n")
+            }
+        };
+        bot.send_message(chat_id, text).send_retrying().await?;
+        Ok(())
+    }
+
+    pub async fn new_group(
+        bot: Bot,
+        message: Message,
+        group: String,
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
+        let chat_id = message.chat.id;
+
+        let text = match group_name(&group) {
+            None => i18n::text(language, "invalid-group"),
+            Some(group) => match chat_storage.add_group(chat_id, &group).await? {
+                None => i18n::text(language, "not-started"),
+                Some(false) => i18n::format(language, "group-exists", &[("group", &group)]),
+                Some(true) => i18n::format(language, "group-created", &[("group", &group)]),
+            },
+        };
+        bot.send_message(chat_id, text).send_retrying().await?;
+        Ok(())
+    }
+
+    pub async fn delete_group(
+        bot: Bot,
+        message: Message,
+        group: String,
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
+        let chat_id = message.chat.id;
+
+        let text = match group_name(&group) {
+            None => i18n::text(language, "invalid-group"),
+            Some(group) => match chat_storage.remove_group(chat_id, &group).await? {
+                None => i18n::text(language, "not-started"),
+                Some(false) => i18n::format(language, "no-group", &[("group", &group)]),
+                Some(true) => i18n::format(language, "group-deleted", &[("group", &group)]),
+            },
+        };
+        bot.send_message(chat_id, text).send_retrying().await?;
+        Ok(())
+    }
+
+    pub async fn join(
+        bot: Bot,
+        message: Message,
+        group: String,
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
+        let chat_id = message.chat.id;
+
+        let text = match (group.trim(), message.from()) {
+            (_, None) => i18n::text(language, "unknown-sender"),
+            ("", Some(user)) => {
+                forget_username(&chat_storage, chat_id, user).await?;
+                match chat_storage
+                    .add_user(chat_id, user.id, user.full_name())
+                    .await?
+                {
+                    None => i18n::text(language, "not-started"),
+                    Some(()) => {
+                        update_counter_if_buttons(&bot, chat_id, &chat_storage, language).await?;
+                        i18n::format(language, "joined", &[("name", &user.full_name())])
+                    }
+                }
+            }
+            (group, Some(user)) => match group_name(group) {
+                None => i18n::text(language, "invalid-group"),
+                Some(group) => {
+                    match chat_storage
+                        .add_group_user(chat_id, &group, user.id, user.full_name())
+                        .await?
+                    {
+                        None => i18n::format(language, "no-group", &[("group", &group)]),
+                        Some(()) => i18n::format(
+                            language,
+                            "joined-group",
+                            &[("name", &user.full_name()), ("group", &group)],
+                        ),
+                    }
+                }
+            },
+        };
+        reply_briefly(&bot, &message, text).await
+    }
+
+    pub async fn leave(
+        bot: Bot,
+        message: Message,
+        group: String,
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
+        let chat_id = message.chat.id;
+
+        let text = match (group.trim(), message.from()) {
+            (_, None) => i18n::text(language, "unknown-sender"),
+            ("", Some(user)) => {
+                forget_username(&chat_storage, chat_id, user).await?;
+                match chat_storage.remove_user(chat_id, user.id).await? {
+                    None => i18n::text(language, "not-started"),
+                    Some(()) => {
+                        update_counter_if_buttons(&bot, chat_id, &chat_storage, language).await?;
+                        i18n::format(language, "left", &[("name", &user.full_name())])
+                    }
+                }
+            }
+            (group, Some(user)) => match group_name(group) {
+                None => i18n::text(language, "invalid-group"),
+                Some(group) => {
+                    match chat_storage
+                        .remove_group_user(chat_id, &group, user.id)
+                        .await?
+                    {
+                        None => i18n::format(language, "no-group", &[("group", &group)]),
+                        Some(()) => i18n::format(
+                            language,
+                            "left-group",
+                            &[("name", &user.full_name()), ("group", &group)],
+                        ),
+                    }
+                }
+            },
+        };
+        reply_briefly(&bot, &message, text).await
+    }
+
+    /// Replies to the message and deletes the reply after `BRIEF_REPLY_TTL`,
+    /// so that confirmations do not clutter the chat.
+    async fn reply_briefly(bot: &Bot, message: &Message, text: String) -> HandlerResult {
+        let chat_id = message.chat.id;
+        let reply = bot
+            .send_message(chat_id, text)
+            .reply_to_message_id(message.id)
+            .allow_sending_without_reply(true)
+            .send_retrying()
             .await?;
+
+        let bot = bot.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(BRIEF_REPLY_TTL).await;
+            if let Err(err) = bot.delete_message(chat_id, reply.id).send_retrying().await {
+                log::warn!("Failed to delete a reply in chat # {chat_id}: {err}");
+            }
+        });
+        Ok(())
+    }
+
+    /// Whether the sender may not run `command` in this chat, either because
+    /// the chat reserved it for administrators or because it changes that choice.
+    ///
+    /// When the sender's rights cannot be checked, the command is not run.
+    pub async fn admin_required(
+        bot: Bot,
+        message: Message,
+        command: Command,
+        chat_storage: Arc<dyn Storage>,
+    ) -> bool {
+        let changes_settings =
+            matches!(&command, Command::MinasanAdminOnly(commands) if !commands.trim().is_empty());
+        let reserved = match chat_storage.get_settings(message.chat.id).await {
+            Ok(settings) => settings
+                .unwrap_or_else(|| chat_storage.defaults())
+                .admin_commands
+                .contains(command.name()),
+            Err(err) => {
+                log::error!(
+                    "Failed to get settings of chat # {}: {err}",
+                    message.chat.id
+                );
+                true
+            }
+        };
+        if !changes_settings && !reserved {
+            return false;
         }
+
+        match is_admin(&bot, &message).await {
+            Ok(admin) => !admin,
+            Err(err) => {
+                log::warn!(
+                    "Failed to check rights of the sender in chat # {}: {err}",
+                    message.chat.id
+                );
+                true
+            }
+        }
+    }
+
+    pub async fn deny(
+        bot: Bot,
+        message: Message,
+        command: Command,
+        language: Language,
+    ) -> HandlerResult {
+        let command = command.name();
+        bot.send_message(
+            message.chat.id,
+            i18n::format(language, "admins-only", &[("command", &command)]),
+        )
+        .reply_to_message_id(message.id)
+        .allow_sending_without_reply(true)
+        .send_retrying()
+        .await?;
         Ok(())
     }
 
-    async fn create_poll(
+    pub async fn admin_only(
+        bot: Bot,
+        message: Message,
+        commands: String,
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
+        let chat_id = message.chat.id;
+
+        let text = if commands.trim().is_empty() {
+            let settings = chat_storage
+                .get_settings(chat_id)
+                .await?
+                .unwrap_or_else(|| chat_storage.defaults());
+            describe_admin_commands(&settings, language)
+        } else {
+            match command_names(&commands) {
+                Err(unknown) => i18n::format(language, "unknown-command", &[("command", &unknown)]),
+                Ok(admin_commands) => {
+                    let mut settings = chat_storage
+                        .get_settings(chat_id)
+                        .await?
+                        .unwrap_or_else(|| chat_storage.defaults());
+                    settings.admin_commands = admin_commands;
+                    match chat_storage
+                        .update_settings(chat_id, settings.clone())
+                        .await?
+                    {
+                        None => i18n::text(language, "not-started"),
+                        Some(()) => describe_admin_commands(&settings, language),
+                    }
+                }
+            }
+        };
+        bot.send_message(chat_id, text).send_retrying().await?;
+        Ok(())
+    }
+
+    pub async fn settings(
+        bot: Bot,
+        message: Message,
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
+        let chat_id = message.chat.id;
+
+        let Some(settings) = chat_storage.get_settings(chat_id).await? else {
+            bot.send_message(chat_id, i18n::text(language, "not-started"))
+                .send_retrying()
+                .await?;
+            return Ok(());
+        };
+        bot.send_message(chat_id, settings_text(&settings, language))
+            .reply_markup(settings_keyboard(&settings, language))
+            .send_retrying()
+            .await?;
+        Ok(())
+    }
+
+    /// Whether the button pressed is one of the settings menu.
+    pub fn is_settings_query(query: CallbackQuery) -> bool {
+        query
+            .data
+            .as_deref()
+            .is_some_and(|data| data.starts_with(SETTINGS))
+    }
+
+    /// Changes the setting of the button pressed, only administrators may.
+    pub async fn update_settings(
+        bot: Bot,
+        query: CallbackQuery,
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> HandlerResult {
+        let action = query
+            .data
+            .as_deref()
+            .and_then(|data| data.strip_prefix(SETTINGS));
+        let (Some(message), Some(action)) = (&query.message, action) else {
+            bot.answer_callback_query(query.id).send_retrying().await?;
+            return Ok(());
+        };
+        let chat_id = message.chat.id;
+
+        if !is_privileged(&bot, &message.chat, query.from.id).await? {
+            bot.answer_callback_query(query.id)
+                .text(i18n::text(language, "settings-admins-only"))
+                .send_retrying()
+                .await?;
+            return Ok(());
+        }
+        let Some(old_settings) = chat_storage.get_settings(chat_id).await? else {
+            bot.answer_callback_query(query.id)
+                .text(i18n::text(language, "settings-not-started"))
+                .send_retrying()
+                .await?;
+            return Ok(());
+        };
+
+        let mut settings = old_settings.clone();
+        let mut answer = None;
+        let mut admin_menu = false;
+        match action {
+            "language" => {
+                // Following the sender comes first, then every language.
+                let choices = [None].into_iter().chain(Language::ALL.map(Some));
+                let choices = choices.collect::<Vec<_>>();
+                let current = choices
+                    .iter()
+                    .position(|&choice| choice == settings.language)
+                    .unwrap_or_default();
+                settings.language = choices[(current + 1) % choices.len()];
+            }
+            "consent" => {
+                settings.consent = match settings.consent {
+                    ConsentMode::Poll => ConsentMode::Buttons,
+                    ConsentMode::Buttons => ConsentMode::Poll,
+                };
+                answer = Some("settings-restart");
+            }
+            "cooldown" => {
+                let cooldown = settings.cooldown.as_secs();
+                let next = COOLDOWNS.into_iter().find(|&c| c > cooldown).unwrap_or(0);
+                settings.cooldown = Duration::from_secs(next);
+            }
+            "delete" => {
+                settings.delete_commands = !settings.delete_commands;
+                if settings.delete_commands {
+                    answer = Some("settings-delete-right");
+                }
+            }
+            "admin" => admin_menu = true,
+            "back" => {}
+            "close" => {
+                if let Err(err) = bot
+                    .delete_message(chat_id, message.id)
+                    .send_retrying()
+                    .await
+                {
+                    log::warn!("Failed to delete the settings menu in chat # {chat_id}: {err}");
+                }
+                bot.answer_callback_query(query.id).send_retrying().await?;
+                return Ok(());
+            }
+            action => {
+                let name = action
+                    .strip_prefix("admin:")
+                    .and_then(|name| command_names(name).ok())
+                    .and_then(|names| names.into_iter().next());
+                match name {
+                    Some(name) => {
+                        if !settings.admin_commands.remove(&name) {
+                            settings.admin_commands.insert(name);
+                        }
+                        admin_menu = true;
+                    }
+                    None => log::warn!("Unknown settings action {action:?} in chat # {chat_id}"),
+                }
+            }
+        }
+
+        if settings != old_settings {
+            chat_storage
+                .update_settings(chat_id, settings.clone())
+                .await?;
+        }
+        // The menu follows the language chosen right away.
+        let language = settings.language.unwrap_or(language);
+        let keyboard = if admin_menu {
+            admin_commands_keyboard(&settings, language)
+        } else {
+            settings_keyboard(&settings, language)
+        };
+        let result = bot
+            .edit_message_text(chat_id, message.id, settings_text(&settings, language))
+            .reply_markup(keyboard)
+            .send_retrying()
+            .await;
+        match result {
+            // Another administrator may have pressed the same button meanwhile.
+            Ok(_) | Err(RequestError::Api(ApiError::MessageNotModified)) => {}
+            Err(err) => log::warn!("Failed to update the settings menu in chat # {chat_id}: {err}"),
+        }
+        let mut request = bot.answer_callback_query(query.id);
+        if let Some(answer) = answer {
+            request = request.text(i18n::text(language, answer));
+        }
+        request.send_retrying().await?;
+        Ok(())
+    }
+
+    /// Deletes the command if the chat chose so, see `ChatSettings::delete_commands`.
+    pub async fn delete_command(bot: Bot, message: Message, chat_storage: Arc<dyn Storage>) {
+        let chat_id = message.chat.id;
+        match chat_storage.get_settings(chat_id).await {
+            Ok(Some(settings)) if settings.delete_commands => {
+                if let Err(err) = bot
+                    .delete_message(chat_id, message.id)
+                    .send_retrying()
+                    .await
+                {
+                    log::warn!("Failed to delete a command in chat # {chat_id}: {err}");
+                }
+            }
+            Ok(_) => {}
+            Err(err) => log::error!("Failed to get settings of chat # {chat_id}: {err}"),
+        }
+    }
+
+    fn settings_text(settings: &ChatSettings, language: Language) -> String {
+        [
+            i18n::text(language, "settings"),
+            String::new(),
+            describe_language(settings, language),
+            describe_consent(settings, language),
+            describe_cooldown(settings, language),
+            describe_delete_commands(settings, language),
+            describe_admin_commands(settings, language),
+        ]
+        .join("\n")
+    }
+
+    fn describe_language(settings: &ChatSettings, language: Language) -> String {
+        let value = match settings.language {
+            Some(chosen) => chosen.name().to_string(),
+            None => i18n::text(language, "language-auto"),
+        };
+        i18n::format(language, "settings-language", &[("value", &value)])
+    }
+
+    fn describe_consent(settings: &ChatSettings, language: Language) -> String {
+        let value = i18n::text(
+            language,
+            match settings.consent {
+                ConsentMode::Poll => "consent-poll",
+                ConsentMode::Buttons => "consent-buttons",
+            },
+        );
+        i18n::format(language, "settings-consent", &[("value", &value)])
+    }
+
+    fn describe_cooldown(settings: &ChatSettings, language: Language) -> String {
+        let value = if settings.cooldown.is_zero() {
+            i18n::text(language, "cooldown-none")
+        } else {
+            humantime::format_duration(settings.cooldown).to_string()
+        };
+        i18n::format(language, "settings-cooldown", &[("value", &value)])
+    }
+
+    fn describe_delete_commands(settings: &ChatSettings, language: Language) -> String {
+        let value = i18n::text(
+            language,
+            if settings.delete_commands {
+                "switch-on"
+            } else {
+                "switch-off"
+            },
+        );
+        i18n::format(language, "settings-delete", &[("value", &value)])
+    }
+
+    fn settings_button(text: String, action: &str) -> InlineKeyboardButton {
+        InlineKeyboardButton::callback(text, format!("{SETTINGS}{action}"))
+    }
+
+    fn settings_keyboard(settings: &ChatSettings, language: Language) -> InlineKeyboardMarkup {
+        InlineKeyboardMarkup::new([
+            [settings_button(
+                describe_language(settings, language),
+                "language",
+            )],
+            [settings_button(
+                describe_consent(settings, language),
+                "consent",
+            )],
+            [settings_button(
+                describe_cooldown(settings, language),
+                "cooldown",
+            )],
+            [settings_button(
+                describe_delete_commands(settings, language),
+                "delete",
+            )],
+            [settings_button(
+                i18n::text(language, "settings-admin-commands"),
+                "admin",
+            )],
+            [settings_button(
+                i18n::text(language, "settings-close"),
+                "close",
+            )],
+        ])
+    }
+
+    /// Toggles of every command, marked if only administrators may run it.
+    fn admin_commands_keyboard(
+        settings: &ChatSettings,
+        language: Language,
+    ) -> InlineKeyboardMarkup {
+        let buttons = Command::bot_commands()
+            .into_iter()
+            .map(|command| {
+                let name = command.command.trim_start_matches('/');
+                let mark = if settings.admin_commands.contains(name) {
+                    "✅ "
+                } else {
+                    ""
+                };
+                settings_button(format!("{mark}/{name}"), &format!("admin:{name}"))
+            })
+            .collect::<Vec<_>>();
+        let mut rows = buttons.chunks(2).map(<[_]>::to_vec).collect::<Vec<_>>();
+        rows.push(vec![settings_button(
+            i18n::text(language, "settings-back"),
+            "back",
+        )]);
+        InlineKeyboardMarkup::new(rows)
+    }
+
+    /// Deletes the consent message, which members may have deleted already.
+    async fn delete_poll(bot: &Bot, chat_id: ChatId, message_id: MessageId) -> HandlerResult {
+        match bot
+            .delete_message(chat_id, message_id)
+            .send_retrying()
+            .await
+        {
+            Ok(_) | Err(RequestError::Api(ApiError::MessageToDeleteNotFound)) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Private chats have no administrators, their only member may do anything.
+    async fn is_admin(bot: &Bot, message: &Message) -> Result<bool, RequestError> {
+        if message.chat.is_private() {
+            return Ok(true);
+        }
+        // Anonymous administrators send messages on behalf of the chat itself.
+        if message
+            .sender_chat()
+            .is_some_and(|chat| chat.id == message.chat.id)
+        {
+            return Ok(true);
+        }
+        let Some(user) = message.from() else {
+            return Ok(false);
+        };
+        is_privileged(bot, &message.chat, user.id).await
+    }
+
+    async fn is_privileged(bot: &Bot, chat: &Chat, user_id: UserId) -> Result<bool, RequestError> {
+        if chat.is_private() {
+            return Ok(true);
+        }
+        let member = bot
+            .get_chat_member(chat.id, user_id)
+            .send_retrying()
+            .await?;
+        Ok(member.is_privileged())
+    }
+
+    fn describe_admin_commands(settings: &ChatSettings, language: Language) -> String {
+        if settings.admin_commands.is_empty() {
+            return i18n::text(language, "everyone-can");
+        }
+        let commands = settings
+            .admin_commands
+            .iter()
+            .map(|name| format!("/{name}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        i18n::format(language, "admins-can", &[("commands", &commands)])
+    }
+
+    /// Parses `kill /minasanrestart`-like lists, `none` being the empty one.
+    /// Returns the first word that is not a command on failure.
+    pub fn command_names(text: &str) -> Result<BTreeSet<String>, String> {
+        let known = Command::bot_commands()
+            .into_iter()
+            .map(|command| command.command.trim_start_matches('/').to_string())
+            .collect::<BTreeSet<_>>();
+
+        let mut names = BTreeSet::new();
+        for word in text.split_whitespace() {
+            let word = word.trim_start_matches('/').to_lowercase();
+            if word == "none" {
+                continue;
+            }
+            let name = if known.contains(&word) {
+                word
+            } else {
+                let name = format!("minasan{word}");
+                if !known.contains(&name) {
+                    return Err(word);
+                }
+                name
+            };
+            names.insert(name);
+        }
+        Ok(names)
+    }
+
+    /// Group names are single lowercase words, so that in `/minasan #<group> [text]`
+    /// the first whitespace ends the name and the rest is the text.
+    fn group_name(name: &str) -> Option<String> {
+        let name = name.trim().to_lowercase();
+        let valid = !name.is_empty()
+            && name.chars().count() <= 32
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+        valid.then_some(name)
+    }
+
+    /// Sends the chunks in order, carrying on past failed ones,
+    /// and lets the chat know if some of them did not make it.
+    async fn send_chunks(
+        bot: &Bot,
+        chat_id: ChatId,
+        reply_to: Option<MessageId>,
+        chunks: Vec<mentions::Chunk>,
+        language: Language,
+    ) -> HandlerResult {
+        let total = chunks.len();
+        let mut first_error = None;
+        let mut failed = 0;
+
+        for chunk in chunks {
+            let mut request = bot
+                .send_message(chat_id, chunk.text)
+                .entities(chunk.entities);
+            if let Some(message_id) = reply_to {
+                request = request
+                    .reply_to_message_id(message_id)
+                    .allow_sending_without_reply(true);
+            }
+
+            if let Err(err) = request.send_retrying().await {
+                log::warn!("Failed to send mentions to chat # {chat_id}: {err}");
+                failed += 1;
+                first_error.get_or_insert(err);
+            }
+        }
+
+        match first_error {
+            Some(err) if failed == total => Err(err.into()),
+            Some(_) => {
+                let text = i18n::format(
+                    language,
+                    "chunks-failed",
+                    &[("failed", &failed), ("total", &total)],
+                );
+                bot.send_message(chat_id, text).send_retrying().await?;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Asks the chat members for consent the way the chat chose,
+    /// returns the message to be stored with the chat and that way.
+    async fn create_consent(
         bot: Bot,
         chat_id: ChatId,
-        chat_storage: Arc<ChatStorage>,
-    ) -> Result<MessageId, RequestError> {
-        let question_str = "\
-            Do you consent to be tagged by `minasan` bot, \
-            via submission of your @username?\
-            ";
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> Result<(MessageId, ConsentMode), Box<dyn Error + Send + Sync>> {
+        let settings = chat_storage
+            .get_settings(chat_id)
+            .await?
+            .unwrap_or_else(|| chat_storage.defaults());
+        match settings.consent {
+            ConsentMode::Poll => {
+                let message_id = create_poll(bot, chat_id, chat_storage, language).await?;
+                Ok((message_id, ConsentMode::Poll))
+            }
+            ConsentMode::Buttons => {
+                let message = bot
+                    .send_message(chat_id, consent_text(0, language))
+                    .reply_markup(consent_keyboard(language))
+                    .send_retrying()
+                    .await?;
+                chat_storage
+                    .update_message(chat_id, message.id, ConsentMode::Buttons)
+                    .await?;
+                Ok((message.id, ConsentMode::Buttons))
+            }
+        }
+    }
 
-        let poll_options = POLL_OPTIONS.into_iter().map(String::from);
+    fn consent_text(consented: usize, language: Language) -> String {
+        let question = i18n::text(language, "consent-question");
+        let counter = i18n::format(language, "consented", &[("count", &consented)]);
+        format!("{question}\n\n{counter}")
+    }
+
+    fn consent_keyboard(language: Language) -> InlineKeyboardMarkup {
+        InlineKeyboardMarkup::new([[
+            InlineKeyboardButton::callback(i18n::text(language, "tag-me"), TAG_ME),
+            InlineKeyboardButton::callback(i18n::text(language, "dont-tag-me"), DONT_TAG_ME),
+        ]])
+    }
 
-        let mut poll_payload = SendPoll::new(chat_id, question_str, poll_options);
-        poll_payload.is_anonymous = Some(false);
+    /// Polls of the bot ask in the language of the chat at the time,
+    /// which may have changed since.
+    fn is_consent_question(question: &str) -> bool {
+        question == LEGACY_CONSENT_QUESTION
+            || Language::ALL
+                .into_iter()
+                .any(|language| question == i18n::text(language, "consent-question"))
+    }
 
-        let message: Message = JsonRequest::new(bot, poll_payload).send().await?;
+    async fn create_poll(
+        bot: Bot,
+        chat_id: ChatId,
+        chat_storage: Arc<dyn Storage>,
+        language: Language,
+    ) -> Result<MessageId, Box<dyn Error + Send + Sync>> {
+        // The order of the options is what `apply_answer` counts on.
+        let poll_options = ["poll-yes", "poll-no"].map(|key| i18n::text(language, key));
+
+        let message = bot
+            .send_poll(
+                chat_id,
+                i18n::text(language, "consent-question"),
+                poll_options,
+            )
+            .is_anonymous(false)
+            .send_retrying()
+            .await?;
 
         let poll_id = match message.kind {
             MessageKind::Common(msg) => match msg.media_kind {
@@ -223,8 +1533,60 @@ pub mod endpoints {
             _ => unreachable!("Wrong MessageKind for Poll!"),
         };
 
-        chat_storage.update_message(chat_id, message.id).await;
-        chat_storage.update_poll(chat_id, poll_id).await;
+        chat_storage
+            .update_message(chat_id, message.id, ConsentMode::Poll)
+            .await?;
+        chat_storage.update_poll(chat_id, poll_id).await?;
         Ok(message.id)
     }
+
+    // Here on only are the tests for command names and arguments parsing.
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use pretty_assertions::assert_eq;
+
+        #[test]
+        fn test_command_names() {
+            assert_eq!(
+                command_names("kill /minasanRestart minasanpoll").unwrap(),
+                BTreeSet::from([
+                    "minasankill".to_string(),
+                    "minasanpoll".to_string(),
+                    "minasanrestart".to_string(),
+                ])
+            );
+            assert_eq!(command_names("minasan").unwrap().len(), 1);
+            assert!(command_names("none").unwrap().is_empty());
+            assert_eq!(command_names("kill dance"), Err("dance".to_string()));
+        }
+
+        #[test]
+        fn test_name() {
+            let arg = String::new;
+            let commands = [
+                Command::MinasanHelp,
+                Command::Minasan(arg()),
+                Command::MinasanKill,
+                Command::MinasanPoll,
+                Command::MinasanStart,
+                Command::MinasanRestart,
+                Command::MinasanGroups,
+                Command::MinasanNewGroup(arg()),
+                Command::MinasanDelGroup(arg()),
+                Command::MinasanJoin(arg()),
+                Command::MinasanLeave(arg()),
+                Command::MinasanMode(arg()),
+                Command::MinasanAdminOnly(arg()),
+                Command::MinasanSettings,
+            ];
+            // Names follow the rename rule of `BotCommands`.
+            let names = Command::bot_commands()
+                .into_iter()
+                .map(|command| command.command.trim_start_matches('/').to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(commands.map(|command| command.name()).to_vec(), names);
+        }
+    }
 }
@@ -0,0 +1,162 @@
+// Configuration file, e.g. `minasan.toml`. Options given by the environment
+// or on the command line take precedence over the ones in the file.
+
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer};
+use simplelog::LevelFilter;
+use url::Url;
+
+use crate::cli::{parse_duration, parse_secret_token, Backend};
+use crate::settings::{ConsentMode, Language};
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Token of the bot, `token_file` keeps it out of the configuration.
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    #[serde(deserialize_with = "from_str")]
+    pub log_level: Option<LevelFilter>,
+    #[serde(deserialize_with = "from_str")]
+    pub api_url: Option<Url>,
+    pub owner_chat_ids: Option<Vec<i64>>,
+    pub storage: StorageConfig,
+    pub webhook: WebhookConfig,
+    /// Settings of the chats that did not choose otherwise.
+    pub chat: ChatConfig,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: Option<Backend>,
+    pub path: Option<String>,
+    pub create_path: Option<bool>,
+    #[serde(deserialize_with = "duration")]
+    pub interval: Option<Duration>,
+    #[serde(deserialize_with = "duration")]
+    pub shutdown_timeout: Option<Duration>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    #[serde(deserialize_with = "from_str")]
+    pub url: Option<Url>,
+    pub listen: Option<SocketAddr>,
+    #[serde(deserialize_with = "secret")]
+    pub secret: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChatConfig {
+    pub consent: Option<ConsentMode>,
+    /// Commands like `kill` or `/minasanrestart`, as `/minasanadminonly` takes them.
+    pub admin_commands: Option<Vec<String>>,
+    pub language: Option<Language>,
+    #[serde(deserialize_with = "duration")]
+    pub cooldown: Option<Duration>,
+    pub delete_commands: Option<bool>,
+}
+
+impl Config {
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+        toml::from_str(&text).map_err(|err| format!("invalid {}: {err}", path.display()))
+    }
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    parse_with(deserializer, |s| s.parse::<T>())
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    parse_with(deserializer, parse_duration)
+}
+
+fn secret<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    parse_with(deserializer, parse_secret_token)
+}
+
+/// Parses the string the way the command line parses the same option.
+fn parse_with<'de, D, T, E>(
+    deserializer: D,
+    parse: impl Fn(&str) -> Result<T, E>,
+) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    E: Display,
+{
+    let s = String::deserialize(deserializer)?;
+    parse(&s).map(Some).map_err(serde::de::Error::custom)
+}
+
+// Here on only are the tests for configuration file parsing.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse() {
+        let config: Config = toml::from_str(
+            r#"
+            token_file = "/run/secrets/minasan"
+            log_level = "debug"
+            owner_chat_ids = [123, 456]
+
+            [storage]
+            backend = "sqlite"
+            path = "minasan.sqlite"
+            interval = "5m"
+
+            [webhook]
+            url = "https://example.com/minasan"
+            listen = "127.0.0.1:8080"
+
+            [chat]
+            consent = "buttons"
+            admin_commands = ["kill"]
+            language = "ja"
+            cooldown = "1m"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.token_file,
+            Some(PathBuf::from("/run/secrets/minasan"))
+        );
+        assert_eq!(config.log_level, Some(LevelFilter::Debug));
+        assert_eq!(config.owner_chat_ids, Some(vec![123, 456]));
+        assert_eq!(config.storage.backend, Some(Backend::Sqlite));
+        assert_eq!(config.storage.interval, Some(Duration::from_secs(300)));
+        assert_eq!(config.storage.shutdown_timeout, None);
+        assert_eq!(config.webhook.url.unwrap().path(), "/minasan");
+        assert_eq!(config.chat.consent, Some(ConsentMode::Buttons));
+        assert_eq!(config.chat.language, Some(Language::Ja));
+        assert_eq!(config.chat.cooldown, Some(Duration::from_secs(60)));
+
+        assert_eq!(toml::from_str::<Config>("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(toml::from_str::<Config>("tokn = \"typo\"").is_err());
+        assert!(toml::from_str::<Config>("[storage]\ninterval = \"soon\"").is_err());
+        assert!(toml::from_str::<Config>("[webhook]\nsecret = \"not so secret\"").is_err());
+        assert!(toml::from_str::<Config>("[storage]\nbackend = \"redis\"").is_err());
+    }
+}
@@ -0,0 +1,1218 @@
+// End-to-end scenarios: updates are polled from a fake Bot API in the same
+// process and go through the dispatcher the bot runs with.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use pretty_assertions::assert_eq;
+use serde_json::{json, Value};
+use teloxide::adaptors::throttle::Limits;
+use teloxide::prelude::*;
+use teloxide::types::{MessageId, UserId};
+use teloxide::update_listeners::{webhooks, UpdateListener};
+use teloxide::utils::command::BotCommands;
+use tokio::sync::{mpsc, Mutex};
+use url::Url;
+
+use crate::api::{Bot, RequestExt};
+use crate::commands::Command;
+use crate::settings::{ChatSettings, ConsentMode, Language};
+use crate::storage::{ChatStorage, Storage};
+use crate::{dispatcher, webhook};
+
+use fake_api::{FakeApi, Request};
+
+mod fake_api;
+
+const CHAT_ID: i64 = -100;
+const ADMIN: u64 = 1;
+const OWNER: i64 = 1000;
+const CO_OWNER: i64 = 1001;
+
+struct Harness {
+    api: FakeApi,
+    bot: Bot,
+    storage: Arc<dyn Storage>,
+    /// Ids of the updates no endpoint handled.
+    unhandled: Mutex<mpsc::UnboundedReceiver<i64>>,
+    last_id: AtomicI64,
+}
+
+impl Harness {
+    async fn new() -> Self {
+        Self::with_storage(ChatStorage::new()).await
+    }
+
+    async fn with_storage(storage: ChatStorage) -> Self {
+        let api = FakeApi::start().await;
+        api.add_admin(ADMIN);
+        // Telegram limits would only slow the scenarios down.
+        let limits = Limits {
+            messages_per_sec_chat: 1000,
+            messages_per_min_chat: 1000,
+            messages_per_min_channel: 1000,
+            messages_per_sec_overall: 1000,
+        };
+        let bot = teloxide::Bot::new("token")
+            .set_api_url(api.url())
+            .throttle(limits);
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+
+        let (unhandled, receiver) = mpsc::unbounded_channel();
+        let mut dispatcher = dispatcher(
+            bot.clone(),
+            Arc::clone(&storage),
+            vec![ChatId(OWNER), ChatId(CO_OWNER)],
+        )
+        .default_handler(move |update| {
+            let _ = unhandled.send(i64::from(update.id));
+            async {}
+        })
+        // Updates are handled one by one in order, so that `settle` knows
+        // every update before its marker has been handled.
+        .distribution_function(|_| Some(()))
+        .build();
+        tokio::spawn(async move { dispatcher.dispatch().await });
+
+        let harness = Self {
+            api,
+            bot,
+            storage,
+            unhandled: Mutex::new(receiver),
+            last_id: AtomicI64::new(0),
+        };
+        harness.settle().await;
+        harness.api.take_requests();
+        harness
+    }
+
+    fn next_id(&self) -> i64 {
+        self.last_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Handles the update, failing if it was reported as an error.
+    async fn dispatch(&self, update: Value) {
+        self.try_dispatch(update).await;
+        let reports = self.api.messages_to(OWNER);
+        assert!(reports.is_empty(), "the update failed: {reports:?}");
+    }
+
+    /// Handles the update, errors go to the error handler.
+    async fn try_dispatch(&self, mut update: Value) {
+        update["update_id"] = json!(self.next_id());
+        self.api.push_update(update);
+        self.settle().await;
+    }
+
+    /// Waits until the updates sent so far are handled. A marker update
+    /// the bot does not handle is sent, it is the last to be handled.
+    async fn settle(&self) {
+        let marker = self.next_id();
+        self.api.push_update(json!({
+            "update_id": marker,
+            "inline_query": {
+                "id": "marker",
+                "from": user(ADMIN),
+                "query": "",
+                "offset": "",
+            },
+        }));
+        let mut unhandled = self.unhandled.lock().await;
+        let id = tokio::time::timeout(Duration::from_secs(10), unhandled.recv())
+            .await
+            .expect("updates were not handled in time");
+        assert_eq!(id, Some(marker), "an update was not handled");
+    }
+
+    /// `user_id` sends `text` to the chat.
+    async fn message(&self, user_id: u64, text: &str) {
+        self.dispatch(json!({"message": self.text_message(user_id, text)}))
+            .await;
+    }
+
+    async fn reply(&self, user_id: u64, text: &str, reply_to: Value) {
+        let mut message = self.text_message(user_id, text);
+        message["reply_to_message"] = reply_to;
+        self.dispatch(json!({"message": message})).await;
+    }
+
+    fn text_message(&self, user_id: u64, text: &str) -> Value {
+        json!({
+            "message_id": self.next_id(),
+            "date": 1_700_000_000,
+            "chat": fake_api::chat(CHAT_ID),
+            "from": user(user_id),
+            "text": text,
+        })
+    }
+
+    async fn answer_poll(&self, user_id: u64, poll_id: &str, option_ids: &[u8]) {
+        self.dispatch(json!({
+            "poll_answer": {
+                "poll_id": poll_id,
+                "user": user(user_id),
+                "option_ids": option_ids,
+            }
+        }))
+        .await;
+    }
+
+    /// `user_id` presses the button with `data` under the message.
+    async fn press(&self, user_id: u64, message: &Value, data: &str) {
+        self.dispatch(json!({
+            "callback_query": {
+                "id": format!("query{}", self.next_id()),
+                "from": user(user_id),
+                "chat_instance": "instance",
+                "message": message,
+                "data": data,
+            }
+        }))
+        .await;
+    }
+
+    /// The bot's own membership in the chat changes, by the administrator.
+    async fn bot_membership(&self, old_status: &str, new_status: &str) {
+        self.dispatch(json!({
+            "my_chat_member": {
+                "chat": fake_api::chat(CHAT_ID),
+                "from": user(ADMIN),
+                "date": 1_700_000_000,
+                "old_chat_member": {"user": fake_api::bot_user(), "status": old_status},
+                "new_chat_member": {"user": fake_api::bot_user(), "status": new_status},
+            }
+        }))
+        .await;
+    }
+
+    /// Starts the chat, returning the poll id.
+    async fn start(&self) -> String {
+        self.message(ADMIN, "/minasanstart").await;
+        let requests = self.api.take_requests();
+        assert_eq!(methods(&requests), ["sendPoll"]);
+        self.storage
+            .get_poll(ChatId(CHAT_ID))
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn users(&self) -> Vec<UserId> {
+        let mut users = self
+            .storage
+            .get_users(ChatId(CHAT_ID))
+            .await
+            .unwrap()
+            .unwrap()
+            .into_keys()
+            .collect::<Vec<_>>();
+        users.sort();
+        users
+    }
+}
+
+fn user(user_id: u64) -> Value {
+    json!({"id": user_id, "is_bot": false, "first_name": format!("user{user_id}")})
+}
+
+/// An address nothing listens on.
+fn free_address() -> std::net::SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// Storage loaded from a dump with the chat file.
+fn load_chat(chat: Value) -> ChatStorage {
+    let dump = tempfile::tempdir().unwrap();
+    std::fs::write(
+        dump.path().join(format!("{CHAT_ID}.json")),
+        chat.to_string(),
+    )
+    .unwrap();
+    ChatStorage::load(dump.path(), false).unwrap()
+}
+
+fn methods(requests: &[Request]) -> Vec<&str> {
+    requests.iter().map(|r| r.method.as_str()).collect()
+}
+
+/// Sorted kinds of updates in `allowed_updates`.
+fn update_kinds(allowed_updates: &Value) -> Vec<&str> {
+    let mut kinds = allowed_updates
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|kind| kind.as_str().unwrap())
+        .collect::<Vec<_>>();
+    kinds.sort();
+    kinds
+}
+
+#[tokio::test]
+async fn test_start() {
+    let harness = Harness::new().await;
+
+    harness.message(ADMIN, "/minasanstart").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendPoll"]);
+    assert_eq!(requests[0].body["chat_id"], json!(CHAT_ID));
+    assert_eq!(requests[0].body["is_anonymous"], json!(false));
+    assert!(harness
+        .storage
+        .get_message_id(ChatId(CHAT_ID))
+        .await
+        .unwrap()
+        .is_some());
+
+    harness.message(2, "/minasanstart@minasan_bot").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendMessage"]);
+    assert!(requests[0].body["text"]
+        .as_str()
+        .unwrap()
+        .contains("already started"));
+}
+
+#[tokio::test]
+async fn test_polling() {
+    let harness = Harness::new().await;
+
+    // Telegram sends `chat_member` updates only when asked to.
+    assert_eq!(
+        update_kinds(&harness.api.allowed_updates()),
+        [
+            "callback_query",
+            "chat_member",
+            "message",
+            "my_chat_member",
+            "poll_answer"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_webhook() {
+    let harness = Harness::new().await;
+
+    let url = Url::parse("https://example.com/webhook").unwrap();
+    let address = free_address();
+    let options = webhooks::Options::new(address, url);
+    let mut listener = webhook::listener(harness.bot.clone(), options)
+        .await
+        .unwrap();
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["setWebhook"]);
+    let body = &requests[0].body;
+    assert_eq!(body["url"], json!("https://example.com/webhook"));
+    assert!(body["secret_token"].is_string());
+    // The same updates as the dispatcher polls for.
+    assert_eq!(
+        update_kinds(&body["allowed_updates"]),
+        update_kinds(&harness.api.allowed_updates())
+    );
+
+    // Stopping keeps the webhook, so that updates can start the bot again.
+    listener.stop_token().stop();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while tokio::net::TcpStream::connect(address).await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the webhook server did not stop");
+    assert!(harness.api.take_requests().is_empty());
+}
+
+#[tokio::test]
+async fn test_poll_answers() {
+    let harness = Harness::new().await;
+    let poll_id = harness.start().await;
+
+    harness.answer_poll(2, &poll_id, &[0]).await;
+    harness.answer_poll(3, &poll_id, &[0]).await;
+    harness.answer_poll(4, &poll_id, &[1]).await;
+    assert_eq!(harness.users().await, [UserId(2), UserId(3)]);
+
+    // Retracted vote.
+    harness.answer_poll(3, &poll_id, &[]).await;
+    assert_eq!(harness.users().await, [UserId(2)]);
+    assert!(harness.api.take_requests().is_empty());
+}
+
+#[tokio::test]
+async fn test_tag() {
+    let harness = Harness::new().await;
+
+    harness.message(2, "/minasan").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendMessage"]);
+    assert!(requests[0].body["text"]
+        .as_str()
+        .unwrap()
+        .contains("/minasanstart"));
+
+    let poll_id = harness.start().await;
+    harness.message(2, "/minasan").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(
+        requests[0].body["text"],
+        json!("Nobody has consented to be tagged yet!!!")
+    );
+
+    harness.answer_poll(2, &poll_id, &[0]).await;
+    harness.answer_poll(3, &poll_id, &[0]).await;
+    harness.message(4, "/minasan standup").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendMessage"]);
+    assert_eq!(requests[0].body["text"], json!("standup\n\nuser2 user3"));
+    let mentioned = requests[0].body["entities"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entity| {
+            assert_eq!(entity["type"], json!("text_mention"));
+            entity["user"]["id"].as_u64().unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(mentioned, [2, 3]);
+}
+
+#[tokio::test]
+async fn test_tag_group() {
+    let harness = Harness::new().await;
+    let poll_id = harness.start().await;
+    harness.answer_poll(3, &poll_id, &[0]).await;
+    harness.message(2, "/minasannewgroup devs").await;
+    harness.message(2, "/minasanjoin devs").await;
+    harness.api.take_requests();
+
+    harness.message(4, "/minasan #Devs deploy is done").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(requests[0].body["text"], json!("deploy is done\n\nuser2"));
+
+    // Text starting with a group name is announced to everyone.
+    harness.message(4, "/minasan devs are down").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(requests[0].body["text"], json!("devs are down\n\nuser3"));
+
+    harness.message(4, "/minasan #ops are down").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendMessage"]);
+    assert_eq!(requests[0].body["text"], json!("There is no group `ops`."));
+}
+
+#[tokio::test]
+async fn test_legacy_usernames() {
+    // Consents from before users were tracked by id.
+    let harness = Harness::with_storage(load_chat(json!({
+        "users": [{"id": 2, "name": "user2"}, "@legacy"],
+        "message_id": 123,
+        "poll_id": "poll",
+    })))
+    .await;
+
+    harness.message(4, "/minasan").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(requests[0].body["text"], json!("user2 @legacy"));
+    let entities = &requests[0].body["entities"];
+    assert_eq!(entities[1]["type"], json!("mention"));
+
+    // Voting again replaces the @username with the member.
+    let mut legacy = user(3);
+    legacy["username"] = json!("legacy");
+    harness
+        .dispatch(json!({
+            "poll_answer": {"poll_id": "poll", "user": legacy, "option_ids": [1]}
+        }))
+        .await;
+    harness.message(4, "/minasan").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(requests[0].body["text"], json!("user2"));
+}
+
+#[tokio::test]
+async fn test_join_leave() {
+    let harness = Harness::new().await;
+    let reply = |requests: Vec<Request>| {
+        assert_eq!(methods(&requests), ["sendMessage"]);
+        requests[0].body["text"].as_str().unwrap().to_string()
+    };
+
+    harness.message(2, "/minasanjoin").await;
+    assert!(reply(harness.api.take_requests()).contains("/minasanstart"));
+
+    harness.start().await;
+    harness.message(2, "/minasanjoin").await;
+    assert_eq!(reply(harness.api.take_requests()), "user2 will be tagged.");
+    assert_eq!(harness.users().await, [UserId(2)]);
+
+    // Leaving without having joined changes nothing.
+    harness.message(3, "/minasanleave").await;
+    assert_eq!(
+        reply(harness.api.take_requests()),
+        "user3 will not be tagged."
+    );
+    assert_eq!(harness.users().await, [UserId(2)]);
+    harness.message(2, "/minasanleave").await;
+    assert_eq!(
+        reply(harness.api.take_requests()),
+        "user2 will not be tagged."
+    );
+    assert!(harness.users().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_join_leave_group() {
+    let harness = Harness::new().await;
+    let reply = |requests: Vec<Request>| {
+        assert_eq!(methods(&requests), ["sendMessage"]);
+        requests[0].body["text"].as_str().unwrap().to_string()
+    };
+    let members = || async {
+        let members = harness
+            .storage
+            .get_group(ChatId(CHAT_ID), "devs")
+            .await
+            .unwrap()
+            .unwrap();
+        let mut members = members.into_keys().collect::<Vec<_>>();
+        members.sort();
+        members
+    };
+    harness.start().await;
+
+    harness.message(2, "/minasanjoin devs").await;
+    assert_eq!(
+        reply(harness.api.take_requests()),
+        "There is no group `devs`."
+    );
+    harness.message(2, "/minasanleave devs").await;
+    assert_eq!(
+        reply(harness.api.take_requests()),
+        "There is no group `devs`."
+    );
+    harness.message(2, "/minasanjoin dev ops").await;
+    assert!(reply(harness.api.take_requests()).starts_with("Group name must be"));
+
+    harness.message(2, "/minasannewgroup devs").await;
+    harness.api.take_requests();
+    harness.message(2, "/minasanjoin Devs").await;
+    assert_eq!(reply(harness.api.take_requests()), "user2 joined `devs`.");
+    assert_eq!(members().await, [UserId(2)]);
+    // Joining a group does not consent to be tagged by everyone.
+    assert!(harness.users().await.is_empty());
+
+    harness.message(3, "/minasanleave devs").await;
+    assert_eq!(reply(harness.api.take_requests()), "user3 left `devs`.");
+    assert_eq!(members().await, [UserId(2)]);
+    harness.message(2, "/minasanleave devs").await;
+    assert_eq!(reply(harness.api.take_requests()), "user2 left `devs`.");
+    assert!(members().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_membership() {
+    let harness = Harness::new().await;
+
+    harness.bot_membership("left", "member").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendMessage"]);
+    assert!(requests[0].body["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("Hi! I tag everyone"));
+
+    let poll_id = harness.start().await;
+    harness.answer_poll(2, &poll_id, &[0]).await;
+
+    // Made an administrator, the bot stays.
+    harness.bot_membership("member", "administrator").await;
+    assert!(harness.api.take_requests().is_empty());
+    assert_eq!(harness.users().await, [UserId(2)]);
+
+    // Added back without having been seen leaving, the chat is remembered.
+    harness.bot_membership("left", "member").await;
+    let requests = harness.api.take_requests();
+    assert!(requests[0].body["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("Hi again!"));
+
+    harness.bot_membership("member", "left").await;
+    assert!(harness.api.take_requests().is_empty());
+    assert!(harness
+        .storage
+        .get_users(ChatId(CHAT_ID))
+        .await
+        .unwrap()
+        .is_none());
+    assert!(harness.storage.poll2chat(&poll_id).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_member_left() {
+    let harness = Harness::new().await;
+    let poll_id = harness.start().await;
+    for user_id in [2, 3] {
+        harness.answer_poll(user_id, &poll_id, &[0]).await;
+    }
+    harness.message(2, "/minasannewgroup devs").await;
+    harness.message(2, "/minasanjoin devs").await;
+    harness.message(3, "/minasanjoin devs").await;
+    harness.api.take_requests();
+    let member = |user_id: u64, old_status: &str, new_status: &str| {
+        json!({
+            "chat_member": {
+                "chat": fake_api::chat(CHAT_ID),
+                "from": user(ADMIN),
+                "date": 1_700_000_000,
+                "old_chat_member": {"user": user(user_id), "status": old_status},
+                "new_chat_member": {"user": user(user_id), "status": new_status},
+            }
+        })
+    };
+    let devs = || async {
+        let mut members = harness
+            .storage
+            .get_group(ChatId(CHAT_ID), "devs")
+            .await
+            .unwrap()
+            .unwrap()
+            .into_keys()
+            .collect::<Vec<_>>();
+        members.sort();
+        members
+    };
+
+    // Joining again is not leaving.
+    harness.dispatch(member(2, "left", "member")).await;
+    assert_eq!(harness.users().await, [UserId(2), UserId(3)]);
+
+    // Leaving without a service message, e.g. in a large supergroup.
+    harness.dispatch(member(2, "member", "left")).await;
+    assert!(harness.api.take_requests().is_empty());
+    assert_eq!(harness.users().await, [UserId(3)]);
+    assert_eq!(devs().await, [UserId(3)]);
+
+    let mut kicked = member(3, "member", "kicked");
+    kicked["chat_member"]["new_chat_member"]["until_date"] = json!(0);
+    harness.dispatch(kicked).await;
+    assert!(harness.users().await.is_empty());
+    assert!(devs().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_migration() {
+    const SUPERGROUP_ID: i64 = -1_000_000_000_100;
+    let supergroup = json!({"id": SUPERGROUP_ID, "type": "supergroup", "title": "Test chat"});
+
+    // Telegram tells both the group and the supergroup, in any order.
+    for to_first in [true, false] {
+        let harness = Harness::new().await;
+        let poll_id = harness.start().await;
+        harness.answer_poll(2, &poll_id, &[0]).await;
+        harness.message(2, "/minasannewgroup devs").await;
+        harness.message(2, "/minasanjoin devs").await;
+        harness.api.take_requests();
+
+        let mut migrate_to = harness.text_message(ADMIN, "");
+        migrate_to.as_object_mut().unwrap().remove("text");
+        migrate_to["migrate_to_chat_id"] = json!(SUPERGROUP_ID);
+        let mut migrate_from = migrate_to.clone();
+        migrate_from["chat"] = supergroup.clone();
+        migrate_from["migrate_from_chat_id"] = json!(CHAT_ID);
+        migrate_from
+            .as_object_mut()
+            .unwrap()
+            .remove("migrate_to_chat_id");
+        let updates = if to_first {
+            [migrate_to, migrate_from]
+        } else {
+            [migrate_from, migrate_to]
+        };
+        for message in updates {
+            harness.dispatch(json!({"message": message})).await;
+        }
+        assert!(harness.api.take_requests().is_empty());
+        assert!(harness
+            .storage
+            .get_users(ChatId(CHAT_ID))
+            .await
+            .unwrap()
+            .is_none());
+
+        // Votes in the poll and the groups follow the chat.
+        harness.answer_poll(3, &poll_id, &[0]).await;
+        let users = harness
+            .storage
+            .get_users(ChatId(SUPERGROUP_ID))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(users.len(), 2, "to first: {to_first}");
+        let mut message = harness.text_message(4, "/minasan #devs hi");
+        message["chat"] = supergroup.clone();
+        harness.dispatch(json!({"message": message})).await;
+        let requests = harness.api.take_requests();
+        assert_eq!(requests[0].body["chat_id"], json!(SUPERGROUP_ID));
+        assert_eq!(requests[0].body["text"], json!("hi\n\nuser2"));
+    }
+}
+
+#[tokio::test]
+async fn test_restart() {
+    let harness = Harness::new().await;
+    let poll_id = harness.start().await;
+    let message_id = harness
+        .storage
+        .get_message_id(ChatId(CHAT_ID))
+        .await
+        .unwrap()
+        .unwrap();
+    harness.answer_poll(2, &poll_id, &[0]).await;
+
+    // Only administrators may restart by default.
+    harness.message(2, "/minasanrestart").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["getChatMember", "sendMessage"]);
+    assert!(requests[1].body["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("Only chat administrators"));
+    assert_eq!(harness.users().await, [UserId(2)]);
+
+    harness.message(ADMIN, "/minasanrestart").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(
+        methods(&requests),
+        ["getChatMember", "deleteMessage", "sendPoll"]
+    );
+    assert_eq!(requests[1].body["message_id"], json!(message_id.0));
+    assert!(harness.users().await.is_empty());
+
+    // Answers to the deleted poll are not counted anymore.
+    harness.answer_poll(2, &poll_id, &[0]).await;
+    assert!(harness.users().await.is_empty());
+
+    // A poll deleted by an administrator is replaced all the same.
+    let MessageId(message_id) = harness
+        .storage
+        .get_message_id(ChatId(CHAT_ID))
+        .await
+        .unwrap()
+        .unwrap();
+    harness.api.delete_message(CHAT_ID, i64::from(message_id));
+    harness.message(ADMIN, "/minasanrestart").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(
+        methods(&requests),
+        ["getChatMember", "deleteMessage", "sendPoll"]
+    );
+}
+
+#[tokio::test]
+async fn test_kill() {
+    let harness = Harness::new().await;
+    harness.start().await;
+    let message_id = harness
+        .storage
+        .get_message_id(ChatId(CHAT_ID))
+        .await
+        .unwrap()
+        .unwrap();
+
+    harness.message(ADMIN, "/minasankill").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(
+        methods(&requests),
+        ["getChatMember", "deleteMessage", "sendMessage", "leaveChat"]
+    );
+    assert_eq!(requests[1].body["message_id"], json!(message_id.0));
+    assert!(harness
+        .storage
+        .get_users(ChatId(CHAT_ID))
+        .await
+        .unwrap()
+        .is_none());
+
+    // A poll deleted by an administrator does not keep the bot in the chat.
+    harness.start().await;
+    let MessageId(message_id) = harness
+        .storage
+        .get_message_id(ChatId(CHAT_ID))
+        .await
+        .unwrap()
+        .unwrap();
+    harness.api.delete_message(CHAT_ID, i64::from(message_id));
+    harness.message(ADMIN, "/minasankill").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(
+        methods(&requests),
+        ["getChatMember", "deleteMessage", "sendMessage", "leaveChat"]
+    );
+    assert!(harness
+        .storage
+        .get_users(ChatId(CHAT_ID))
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_lost_poll() {
+    let harness = Harness::new().await;
+    let poll_id = harness.start().await;
+    let MessageId(message_id) = harness
+        .storage
+        .get_message_id(ChatId(CHAT_ID))
+        .await
+        .unwrap()
+        .unwrap();
+
+    // The storage lost the chat, but the poll is still in it.
+    harness.storage.remove_chat(ChatId(CHAT_ID)).await.unwrap();
+    harness.answer_poll(2, &poll_id, &[0]).await;
+
+    let question = "Do you consent to be tagged by `minasan` bot, via mention of your name?";
+    let poll_message = fake_api::poll_message(CHAT_ID, message_id.into(), &poll_id, question);
+    harness.reply(ADMIN, "/minasanstart", poll_message).await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendMessage"]);
+    assert_eq!(harness.users().await, [UserId(2)]);
+    assert_eq!(
+        harness.storage.poll2chat(&poll_id).await.unwrap(),
+        Some(ChatId(CHAT_ID))
+    );
+}
+
+#[tokio::test]
+async fn test_pending_votes() {
+    let harness = Harness::new().await;
+    let poll_id = harness.start().await;
+    let MessageId(message_id) = harness
+        .storage
+        .get_message_id(ChatId(CHAT_ID))
+        .await
+        .unwrap()
+        .unwrap();
+    harness.storage.remove_chat(ChatId(CHAT_ID)).await.unwrap();
+
+    // Answers to the unknown poll are replayed in order once it is found.
+    harness.answer_poll(2, &poll_id, &[0]).await;
+    harness.answer_poll(3, &poll_id, &[0]).await;
+    harness.answer_poll(3, &poll_id, &[]).await;
+    harness.answer_poll(4, &poll_id, &[1]).await;
+    harness.answer_poll(5, "other", &[0]).await;
+    assert!(harness.api.take_requests().is_empty());
+
+    // A poll of another bot is not taken for the lost one.
+    let question = "Do you consent to be tagged by `minasan` bot, via mention of your name?";
+    let mut poll_message = fake_api::poll_message(CHAT_ID, message_id.into(), &poll_id, question);
+    poll_message["from"] = json!({"id": 43, "is_bot": true, "first_name": "other_bot"});
+    harness
+        .reply(ADMIN, "/minasanstart", poll_message.clone())
+        .await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendPoll"]);
+    assert!(harness.users().await.is_empty());
+
+    // Polls made before the bot spoke several languages ask differently.
+    harness.storage.remove_chat(ChatId(CHAT_ID)).await.unwrap();
+    poll_message["from"] = fake_api::bot_user();
+    poll_message["poll"]["question"] =
+        json!("Do you consent to be tagged by `minasan` bot, via submission of your @username?");
+    harness.reply(ADMIN, "/minasanstart", poll_message).await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendMessage"]);
+    assert_eq!(harness.users().await, [UserId(2)]);
+}
+
+#[tokio::test]
+async fn test_poll_recovery() {
+    // Dumps older than poll ids know the poll message but not the poll.
+    let harness = Harness::with_storage(load_chat(json!({
+        "users": [{"id": 2, "name": "user2"}],
+        "message_id": 123,
+        "poll_id": "null",
+    })))
+    .await;
+
+    // Only commands are looked at.
+    let mut message = harness.text_message(5, "");
+    message.as_object_mut().unwrap().remove("text");
+    message["left_chat_member"] = user(5);
+    harness.dispatch(json!({ "message": message })).await;
+    assert!(harness.api.take_requests().is_empty());
+
+    harness.message(2, "/minasan").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendMessage", "sendMessage"]);
+    assert!(requests[0].body["text"]
+        .as_str()
+        .unwrap()
+        .contains("/minasanrestart"));
+    assert_eq!(requests[0].body["reply_to_message_id"], json!(123));
+    assert_eq!(requests[1].body["text"], json!("user2"));
+
+    // The chat is told once.
+    harness.message(2, "/minasan").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendMessage"]);
+
+    harness.message(ADMIN, "/minasanrestart").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(
+        methods(&requests),
+        ["getChatMember", "deleteMessage", "sendPoll"]
+    );
+    assert!(harness
+        .storage
+        .get_poll(ChatId(CHAT_ID))
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+async fn test_poll_recovery_buttons() {
+    // Buttons have no poll to lose.
+    let storage = load_chat(json!({
+        "version": 1,
+        "message_id": 123,
+        "consent": "buttons",
+        "poll_id": null,
+        "users": [],
+        "usernames": [],
+        "groups": {},
+        "settings": {"consent": "buttons"},
+    }));
+    let harness = Harness::with_storage(storage).await;
+    harness.message(2, "/minasan").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendMessage"]);
+}
+
+#[tokio::test]
+async fn test_flood_control() {
+    let harness = Harness::new().await;
+    let poll_id = harness.start().await;
+    harness.answer_poll(2, &poll_id, &[0]).await;
+
+    harness.api.flood(1);
+    harness.message(3, "/minasan").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendMessage", "sendMessage"]);
+    assert_eq!(requests[0].body, requests[1].body);
+}
+
+#[tokio::test]
+async fn test_network_failure() {
+    // Nothing listens at first, so the first attempt fails to connect.
+    let address = free_address();
+    let bot = teloxide::Bot::new("token")
+        .set_api_url(Url::parse(&format!("http://{address}")).unwrap())
+        .throttle(Limits::default());
+    let api = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        FakeApi::start_at(address).await
+    });
+
+    let me = bot.get_me().send_retrying().await.unwrap();
+    assert_eq!(json!(me.id), fake_api::bot_user()["id"]);
+    assert_eq!(methods(&api.await.unwrap().take_requests()), ["getMe"]);
+}
+
+#[tokio::test]
+async fn test_error_report() {
+    let harness = Harness::new().await;
+    harness.api.break_method("sendPoll");
+
+    let message = harness.text_message(ADMIN, "/minasanstart");
+    harness.try_dispatch(json!({"message": message})).await;
+    let requests = harness.api.take_requests();
+    assert_eq!(
+        methods(&requests),
+        ["sendPoll", "sendMessage", "sendMessage", "sendMessage"]
+    );
+    assert_eq!(requests[1].body["chat_id"], json!(CHAT_ID));
+    assert_eq!(
+        requests[1].body["reply_to_message_id"],
+        message["message_id"]
+    );
+    assert!(requests[1].body["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("Sorry"));
+
+    assert_eq!(requests[2].body["chat_id"], json!(OWNER));
+    let report = requests[2].body["text"].as_str().unwrap();
+    assert!(report.contains(&format!("in chat # {CHAT_ID}")));
+    assert!(report.contains("Message: /minasanstart"));
+    assert!(report.contains("broken by the test"));
+    assert_eq!(requests[3].body["chat_id"], json!(CO_OWNER));
+    assert_eq!(requests[3].body["text"], requests[2].body["text"]);
+}
+
+#[tokio::test]
+async fn test_cooldown_failure() {
+    let harness = Harness::new().await;
+    let poll_id = harness.start().await;
+    harness.answer_poll(2, &poll_id, &[0]).await;
+    let settings = ChatSettings {
+        cooldown: Duration::from_secs(60),
+        ..ChatSettings::default()
+    };
+    harness
+        .storage
+        .update_settings(ChatId(CHAT_ID), settings)
+        .await
+        .unwrap();
+
+    // A tag that failed to be sent does not start the cooldown.
+    harness.api.break_method("sendMessage");
+    let message = harness.text_message(3, "/minasan");
+    harness.try_dispatch(json!({ "message": message })).await;
+    harness.api.fix_method("sendMessage");
+    harness.api.take_requests();
+
+    harness.message(3, "/minasan").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(requests[0].body["text"], json!("user2"));
+    harness.message(3, "/minasan").await;
+    let requests = harness.api.take_requests();
+    assert!(requests[0].body["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("Everyone was tagged recently"));
+}
+
+#[tokio::test]
+async fn test_settings() {
+    let harness = Harness::new().await;
+    let poll_id = harness.start().await;
+    harness.answer_poll(2, &poll_id, &[0]).await;
+
+    harness.message(2, "/minasansettings").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendMessage"]);
+    let menu = harness.api.last_message();
+
+    // Only administrators may change the settings.
+    harness.press(2, &menu, "settings:cooldown").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["getChatMember", "answerCallbackQuery"]);
+    assert!(requests[1].body["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("Only chat administrators"));
+
+    harness.press(ADMIN, &menu, "settings:cooldown").await;
+    harness.press(ADMIN, &menu, "settings:delete").await;
+    harness
+        .press(ADMIN, &menu, "settings:admin:minasanpoll")
+        .await;
+    let requests = harness.api.take_requests();
+    assert_eq!(requests[1].body["message_id"], menu["message_id"]);
+    let text = requests[1].body["text"].as_str().unwrap();
+    assert!(text.contains("Cooldown of /minasan: 1m"));
+
+    let settings = harness
+        .storage
+        .get_settings(ChatId(CHAT_ID))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(settings.cooldown, Duration::from_secs(60));
+    assert!(settings.delete_commands);
+    assert!(settings.admin_commands.contains("minasanpoll"));
+
+    // Commands are deleted, and everyone is tagged at most once a minute.
+    harness.message(3, "/minasan").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["deleteMessage", "sendMessage"]);
+    harness.message(3, "/minasan").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["deleteMessage", "sendMessage"]);
+    assert!(requests[1].body["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("Everyone was tagged recently"));
+}
+
+#[tokio::test]
+async fn test_buttons() {
+    let harness = Harness::new().await;
+    harness.start().await;
+    harness.message(ADMIN, "/minasanmode buttons").await;
+    harness.message(ADMIN, "/minasanrestart").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(
+        methods(&requests[requests.len() - 2..]),
+        ["deleteMessage", "sendMessage"]
+    );
+    let consent = harness.api.last_message();
+    assert!(consent["text"].as_str().unwrap().ends_with("Consented: 0."));
+    let buttons = &requests.last().unwrap().body["reply_markup"]["inline_keyboard"][0];
+    assert_eq!(buttons[0]["callback_data"], json!("tag_me"));
+    assert_eq!(buttons[1]["callback_data"], json!("dont_tag_me"));
+
+    harness.press(2, &consent, "tag_me").await;
+    harness.press(3, &consent, "tag_me").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(
+        methods(&requests),
+        [
+            "editMessageText",
+            "answerCallbackQuery",
+            "editMessageText",
+            "answerCallbackQuery"
+        ]
+    );
+    assert_eq!(requests[2].body["message_id"], consent["message_id"]);
+    assert!(requests[2].body["text"]
+        .as_str()
+        .unwrap()
+        .ends_with("Consented: 2."));
+    assert_eq!(requests[3].body["text"], json!("You will be tagged."));
+    assert_eq!(harness.users().await, [UserId(2), UserId(3)]);
+
+    harness.press(2, &consent, "dont_tag_me").await;
+    let requests = harness.api.take_requests();
+    assert!(requests[0].body["text"]
+        .as_str()
+        .unwrap()
+        .ends_with("Consented: 1."));
+    assert_eq!(requests[1].body["text"], json!("You will not be tagged."));
+    assert_eq!(harness.users().await, [UserId(3)]);
+
+    // Buttons of a message the bot no longer follows only tell so.
+    let mut stale = consent.clone();
+    stale["message_id"] = json!(1);
+    harness.press(2, &stale, "tag_me").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["answerCallbackQuery"]);
+    assert_eq!(
+        requests[0].body["text"],
+        json!("This poll is no longer active.")
+    );
+
+    // So do buttons the bot does not know.
+    harness.press(2, &consent, "dance").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["answerCallbackQuery"]);
+    assert_eq!(requests[0].body.get("text"), None);
+    assert_eq!(harness.users().await, [UserId(3)]);
+}
+
+#[tokio::test]
+async fn test_mode_change() {
+    let defaults = ChatSettings {
+        consent: ConsentMode::Buttons,
+        ..ChatSettings::default()
+    };
+    let harness = Harness::with_storage(ChatStorage::new().with_defaults(defaults)).await;
+    harness.message(ADMIN, "/minasanstart").await;
+    harness.message(ADMIN, "/minasanmode poll").await;
+    harness.api.take_requests();
+
+    // The buttons still work until the chat restarts, there is no poll to lose.
+    harness.message(ADMIN, "/minasanhelp").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendMessage"]);
+    assert!(requests[0].body["text"]
+        .as_str()
+        .unwrap()
+        .contains("/minasanstart"));
+
+    // Forwarded buttons would not work, so the message is replied to.
+    let message_id = harness.storage.get_message_id(ChatId(CHAT_ID)).await;
+    harness.message(2, "/minasanpoll").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["sendMessage"]);
+    assert_eq!(
+        requests[0].body["reply_to_message_id"],
+        json!(message_id.unwrap().unwrap().0)
+    );
+
+    // Their counter still follows consent given with a command.
+    harness.message(2, "/minasanjoin").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["editMessageText", "sendMessage"]);
+}
+
+#[tokio::test]
+async fn test_language() {
+    let harness = Harness::new().await;
+    harness.start().await;
+
+    // Unless the chat chose a language, everyone is answered in their own.
+    let mut message = harness.text_message(2, "/minasanpoll");
+    message["from"]["language_code"] = json!("ru");
+    harness.dispatch(json!({"message": message})).await;
+    let requests = harness.api.take_requests();
+    assert_eq!(requests[0].body["text"], "Вот ваш опрос.");
+
+    harness.message(ADMIN, "/minasansettings").await;
+    harness.api.take_requests();
+    let menu = harness.api.last_message();
+    // Following the sender, then English, then Russian.
+    harness.press(ADMIN, &menu, "settings:language").await;
+    harness.press(ADMIN, &menu, "settings:language").await;
+    let requests = harness.api.take_requests();
+    let edit = requests
+        .iter()
+        .rfind(|request| request.method == "editMessageText")
+        .unwrap();
+    // The menu switches to the language chosen right away.
+    assert!(edit.body["text"]
+        .as_str()
+        .unwrap()
+        .starts_with("Настройки этого чата"));
+    let settings = harness.storage.get_settings(ChatId(CHAT_ID)).await;
+    assert_eq!(settings.unwrap().unwrap().language, Some(Language::Ru));
+
+    harness.message(ADMIN, "/minasanhelp").await;
+    let requests = harness.api.take_requests();
+    let help = requests[0].body["text"].as_str().unwrap();
+    assert!(help.contains("/minasanstart — Запускает бота и создаёт опрос."));
+    assert_eq!(help.lines().count(), Command::bot_commands().len());
+}
+
+#[tokio::test]
+async fn test_configured_defaults() {
+    // A chat not started yet already follows the configured defaults.
+    let defaults = ChatSettings {
+        consent: ConsentMode::Buttons,
+        language: Some(Language::Ru),
+        admin_commands: ["minasanstart".to_owned()].into(),
+        ..ChatSettings::default()
+    };
+    let harness = Harness::with_storage(ChatStorage::new().with_defaults(defaults)).await;
+
+    harness.message(2, "/minasanstart").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["getChatMember", "sendMessage"]);
+    assert_eq!(
+        requests[1].body["text"],
+        json!("Только администраторы чата могут использовать `/minasanstart`.")
+    );
+    assert_eq!(
+        harness
+            .storage
+            .get_message_id(ChatId(CHAT_ID))
+            .await
+            .unwrap(),
+        None
+    );
+
+    harness.message(ADMIN, "/minasanstart").await;
+    let requests = harness.api.take_requests();
+    assert_eq!(methods(&requests), ["getChatMember", "sendMessage"]);
+    let consent = requests[1].body["text"].as_str().unwrap();
+    assert!(consent.starts_with("Согласны ли вы"));
+    assert!(consent.ends_with("Согласились: 0."));
+    let buttons = &requests[1].body["reply_markup"]["inline_keyboard"][0];
+    assert_eq!(buttons[0]["callback_data"], json!("tag_me"));
+    assert_eq!(buttons[0]["text"], json!("Отмечать меня"));
+}
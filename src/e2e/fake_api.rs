@@ -0,0 +1,378 @@
+// Fake Bot API served in process: records the requests of the bot and
+// answers them the way Telegram would, as far as the bot can tell.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap};
+use axum::routing::post;
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+use url::Url;
+
+/// Id of the bot user, as returned by `getMe`.
+pub const BOT_ID: u64 = 42;
+
+/// Date of every message, Telegram sends it as a unix timestamp.
+const DATE: i64 = 1_700_000_000;
+
+/// A Bot API method called by the bot, e.g. `sendMessage`, with its parameters.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub body: Value,
+}
+
+#[derive(Default)]
+struct Api {
+    requests: Vec<Request>,
+    /// Messages sent by the bot by chat and message id.
+    messages: HashMap<(i64, i64), Value>,
+    last_message_id: i64,
+    last_poll_id: u64,
+    admins: HashSet<u64>,
+    /// Number of the next messages to reject with `RetryAfter`.
+    flood: usize,
+    /// Methods failing with `Bad Request`.
+    broken: HashSet<String>,
+    /// Updates waiting for `getUpdates`.
+    updates: VecDeque<Value>,
+    /// Wakes up a `getUpdates` waiting for updates.
+    new_updates: Arc<Notify>,
+    /// Update kinds asked for by the first `getUpdates`.
+    allowed_updates: Value,
+}
+
+pub struct FakeApi {
+    url: Url,
+    api: Arc<Mutex<Api>>,
+}
+
+impl FakeApi {
+    /// Starts serving on a free local port.
+    pub async fn start() -> Self {
+        Self::start_at(SocketAddr::from(([127, 0, 0, 1], 0))).await
+    }
+
+    pub async fn start_at(address: SocketAddr) -> Self {
+        let listener = TcpListener::bind(address).unwrap();
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+
+        let api = Arc::new(Mutex::new(Api {
+            last_message_id: 1000,
+            ..Default::default()
+        }));
+        let router = Router::new()
+            .route("/:token/:method", post(handle))
+            .with_state(Arc::clone(&api));
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(router.into_make_service());
+        tokio::spawn(server);
+
+        Self { url, api }
+    }
+
+    pub fn url(&self) -> Url {
+        self.url.clone()
+    }
+
+    /// Requests made since the previous call, but for the polling of updates.
+    pub fn take_requests(&self) -> Vec<Request> {
+        let mut requests = std::mem::take(&mut self.api.lock().unwrap().requests);
+        requests.retain(|request| request.method != "getUpdates");
+        requests
+    }
+
+    /// Queues the update for the next `getUpdates`.
+    pub fn push_update(&self, update: Value) {
+        let mut api = self.api.lock().unwrap();
+        api.updates.push_back(update);
+        api.new_updates.notify_one();
+    }
+
+    /// Update kinds the bot polls for.
+    pub fn allowed_updates(&self) -> Value {
+        self.api.lock().unwrap().allowed_updates.clone()
+    }
+
+    /// Messages the bot sent to the chat.
+    pub fn messages_to(&self, chat_id: i64) -> Vec<Value> {
+        let api = self.api.lock().unwrap();
+        let mut messages = api
+            .messages
+            .iter()
+            .filter(|((chat, _), _)| *chat == chat_id)
+            .map(|(_, message)| message.clone())
+            .collect::<Vec<_>>();
+        messages.sort_by_key(|message| message["message_id"].as_i64());
+        messages
+    }
+
+    /// The message the bot sent last.
+    pub fn last_message(&self) -> Value {
+        let api = self.api.lock().unwrap();
+        api.messages
+            .values()
+            .find(|message| message["message_id"] == json!(api.last_message_id))
+            .unwrap()
+            .clone()
+    }
+
+    /// Deletes the message the bot sent, as a chat administrator would.
+    pub fn delete_message(&self, chat_id: i64, message_id: i64) {
+        self.api
+            .lock()
+            .unwrap()
+            .messages
+            .remove(&(chat_id, message_id));
+    }
+
+    /// Rejects the next `count` messages, asking to retry in a second.
+    pub fn flood(&self, count: usize) {
+        self.api.lock().unwrap().flood = count;
+    }
+
+    /// Makes every call of `method` fail.
+    pub fn break_method(&self, method: &str) {
+        self.api.lock().unwrap().broken.insert(method.to_string());
+    }
+
+    pub fn fix_method(&self, method: &str) {
+        self.api.lock().unwrap().broken.remove(method);
+    }
+
+    /// Makes `getChatMember` report the user as the chat owner.
+    pub fn add_admin(&self, user_id: u64) {
+        self.api.lock().unwrap().admins.insert(user_id);
+    }
+}
+
+pub fn bot_user() -> Value {
+    json!({
+        "id": BOT_ID,
+        "is_bot": true,
+        "first_name": "minasan",
+        "username": "minasan_bot",
+    })
+}
+
+pub fn chat(chat_id: i64) -> Value {
+    if chat_id < 0 {
+        json!({"id": chat_id, "type": "group", "title": "Test chat"})
+    } else {
+        json!({"id": chat_id, "type": "private", "first_name": "user"})
+    }
+}
+
+/// The consent poll as sent by the bot.
+pub fn poll_message(chat_id: i64, message_id: i64, poll_id: &str, question: &str) -> Value {
+    json!({
+        "message_id": message_id,
+        "date": DATE,
+        "chat": chat(chat_id),
+        "from": bot_user(),
+        "poll": {
+            "id": poll_id,
+            "question": question,
+            "options": [
+                {"text": "I do.", "voter_count": 0},
+                {"text": "I don't.", "voter_count": 0},
+            ],
+            "total_voter_count": 0,
+            "is_closed": false,
+            "is_anonymous": false,
+            "type": "regular",
+            "allows_multiple_answers": false,
+        },
+    })
+}
+
+async fn handle(
+    State(api): State<Arc<Mutex<Api>>>,
+    Path((_token, method)): Path<(String, String)>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Json<Value> {
+    // teloxide names the methods `SendMessage`, the documentation - `sendMessage`.
+    let method = method[..1].to_lowercase() + &method[1..];
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let body = match content_type.split_once("boundary=") {
+        Some((_, boundary)) => form_data(boundary, &String::from_utf8_lossy(&body)),
+        None => serde_json::from_slice(&body).unwrap_or(Value::Null),
+    };
+    if method == "getUpdates" {
+        return Json(json!({"ok": true, "result": get_updates(&api, &body).await}));
+    }
+    let mut api = api.lock().unwrap();
+    api.requests.push(Request {
+        method: method.clone(),
+        body: body.clone(),
+    });
+
+    if method.starts_with("send") && api.flood > 0 {
+        api.flood -= 1;
+        return Json(json!({
+            "ok": false,
+            "error_code": 429,
+            "description": "Too Many Requests: retry after 1",
+            "parameters": {"retry_after": 1},
+        }));
+    }
+
+    let result = match method.as_str() {
+        method if api.broken.contains(method) => Err("Bad Request: broken by the test"),
+        "getMe" => {
+            let mut me = bot_user();
+            me["can_join_groups"] = json!(true);
+            me["can_read_all_group_messages"] = json!(false);
+            me["supports_inline_queries"] = json!(false);
+            Ok(me)
+        }
+        "sendMessage" => Ok(api.send(&body["chat_id"], |message| {
+            message["text"] = body["text"].clone();
+            if !body["entities"].is_null() {
+                message["entities"] = body["entities"].clone();
+            }
+        })),
+        "sendPoll" => {
+            api.last_poll_id += 1;
+            let poll_id = format!("poll{}", api.last_poll_id);
+            let chat_id = body["chat_id"].as_i64().unwrap();
+            let question = body["question"].as_str().unwrap().to_string();
+            Ok(api.send(&body["chat_id"], |message| {
+                *message = poll_message(
+                    chat_id,
+                    message["message_id"].as_i64().unwrap(),
+                    &poll_id,
+                    &question,
+                );
+            }))
+        }
+        "forwardMessage" => {
+            let key = (
+                body["from_chat_id"].as_i64().unwrap(),
+                body["message_id"].as_i64().unwrap(),
+            );
+            match api.messages.get(&key).cloned() {
+                None => Err("Bad Request: message to forward not found"),
+                Some(original) => Ok(api.send(&body["chat_id"], |message| {
+                    let id = message["message_id"].clone();
+                    *message = original;
+                    message["message_id"] = id;
+                    message["forward_date"] = json!(DATE);
+                    message["forward_from"] = bot_user();
+                })),
+            }
+        }
+        "editMessageText" => {
+            let key = (
+                body["chat_id"].as_i64().unwrap(),
+                body["message_id"].as_i64().unwrap(),
+            );
+            match api.messages.get_mut(&key) {
+                None => Err("Bad Request: message to edit not found"),
+                Some(message) => {
+                    message["text"] = body["text"].clone();
+                    Ok(message.clone())
+                }
+            }
+        }
+        "getChatMember" => {
+            let user_id = body["user_id"].as_u64().unwrap();
+            let status = if api.admins.contains(&user_id) {
+                "creator"
+            } else {
+                "member"
+            };
+            Ok(json!({
+                "status": status,
+                "is_anonymous": false,
+                "user": {"id": user_id, "is_bot": false, "first_name": "user"},
+            }))
+        }
+        "getWebhookInfo" => Ok(json!({
+            "url": "",
+            "has_custom_certificate": false,
+            "pending_update_count": 0,
+        })),
+        "deleteMessage" => {
+            let key = (
+                body["chat_id"].as_i64().unwrap(),
+                body["message_id"].as_i64().unwrap(),
+            );
+            match api.messages.remove(&key) {
+                None => Err("Bad Request: message to delete not found"),
+                Some(_) => Ok(json!(true)),
+            }
+        }
+        "setWebhook" | "deleteWebhook" | "leaveChat" | "answerCallbackQuery" => Ok(json!(true)),
+        _ => Err("Not Found: method not found"),
+    };
+
+    Json(match result {
+        Ok(result) => json!({"ok": true, "result": result}),
+        Err(description) => json!({"ok": false, "error_code": 400, "description": description}),
+    })
+}
+
+/// Parameters of a `multipart/form-data` body, which teloxide sends
+/// for methods that may upload files. Values in JSON are parsed.
+fn form_data(boundary: &str, body: &str) -> Value {
+    let fields = body
+        .split(&format!("--{boundary}"))
+        .filter_map(|part| {
+            let (headers, value) = part.split_once("\r\n\r\n")?;
+            let name = headers.split("name=\"").nth(1)?.split('"').next()?;
+            let value = value.trim_end_matches("\r\n");
+            let value = serde_json::from_str(value).unwrap_or_else(|_| json!(value));
+            Some((name.to_string(), value))
+        })
+        .collect();
+    Value::Object(fields)
+}
+
+/// Long polling: waits up to the `timeout` of the request for updates to come.
+async fn get_updates(api: &Mutex<Api>, body: &Value) -> Value {
+    let new_updates = {
+        let mut api = api.lock().unwrap();
+        api.requests.push(Request {
+            method: "getUpdates".to_string(),
+            body: body.clone(),
+        });
+        if api.allowed_updates.is_null() {
+            api.allowed_updates = body["allowed_updates"].clone();
+        }
+        Arc::clone(&api.new_updates)
+    };
+    let timeout = Duration::from_secs(body["timeout"].as_u64().unwrap_or_default());
+    // A permit left by `push_update` ends the wait right away.
+    let _ = tokio::time::timeout(timeout, new_updates.notified()).await;
+    Value::from(api.lock().unwrap().updates.drain(..).collect::<Vec<_>>())
+}
+
+impl Api {
+    /// Sends a message with a new id to the chat, `fill` sets its content.
+    fn send(&mut self, chat_id: &Value, fill: impl FnOnce(&mut Value)) -> Value {
+        self.last_message_id += 1;
+        let chat_id = chat_id.as_i64().unwrap();
+        let mut message = json!({
+            "message_id": self.last_message_id,
+            "date": DATE,
+            "chat": chat(chat_id),
+            "from": bot_user(),
+        });
+        fill(&mut message);
+        self.messages
+            .insert((chat_id, self.last_message_id), message.clone());
+        message
+    }
+}
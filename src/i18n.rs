@@ -0,0 +1,132 @@
+// Messages of the bot in every language it speaks. They are kept in
+// `locales/<code>.toml`, so translating the bot does not touch the code.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+use crate::settings::Language;
+
+type Catalog = HashMap<String, String>;
+
+static CATALOGS: OnceLock<HashMap<Language, Catalog>> = OnceLock::new();
+
+fn source(language: Language) -> &'static str {
+    match language {
+        Language::En => include_str!("../locales/en.toml"),
+        Language::Ru => include_str!("../locales/ru.toml"),
+        Language::Ja => include_str!("../locales/ja.toml"),
+    }
+}
+
+fn catalog(language: Language) -> &'static Catalog {
+    let catalogs = CATALOGS.get_or_init(|| {
+        Language::ALL
+            .into_iter()
+            .map(|language| {
+                let catalog = toml::from_str(source(language))
+                    .unwrap_or_else(|err| panic!("invalid catalog of {}: {err}", language.code()));
+                (language, catalog)
+            })
+            .collect()
+    });
+    &catalogs[&language]
+}
+
+/// The message `key` in `language`.
+pub fn text(language: Language, key: &str) -> String {
+    format(language, key, &[])
+}
+
+/// The message `key` in `language` with every `{name}` replaced by its value.
+/// A message missing from the catalog is taken from the English one.
+pub fn format(language: Language, key: &str, args: &[(&str, &(dyn Display + Sync))]) -> String {
+    let message = catalog(language)
+        .get(key)
+        .or_else(|| catalog(Language::En).get(key));
+    let Some(message) = message else {
+        log::error!("There is no message {key}.");
+        return key.to_string();
+    };
+    // A single scan, so that values containing `{name}` are left as they are.
+    let mut formatted = String::with_capacity(message.len());
+    let mut rest = message.as_str();
+    while let Some(start) = rest.find('{') {
+        formatted.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest[1..].split_once('}').and_then(|(name, _)| {
+            let (_, value) = args.iter().find(|(arg, _)| *arg == name)?;
+            Some((name.len() + 2, value))
+        });
+        match value {
+            Some((len, value)) => {
+                formatted.push_str(&value.to_string());
+                rest = &rest[len..];
+            }
+            None => {
+                formatted.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    formatted.push_str(rest);
+    formatted
+}
+
+// Here on only are the tests for the message catalogs.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::Command;
+    use std::collections::BTreeSet;
+    use teloxide::utils::command::BotCommands;
+
+    fn placeholders(message: &str) -> BTreeSet<&str> {
+        message
+            .split('{')
+            .skip(1)
+            .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+            .collect()
+    }
+
+    #[test]
+    fn test_catalogs() {
+        let english = catalog(Language::En);
+        for language in Language::ALL {
+            let catalog = catalog(language);
+            let keys: BTreeSet<_> = catalog.keys().collect();
+            assert_eq!(keys, english.keys().collect(), "keys of {language:?}");
+            for (key, message) in catalog {
+                assert_eq!(
+                    placeholders(message),
+                    placeholders(&english[key]),
+                    "placeholders of {key} in {language:?}"
+                );
+            }
+        }
+        for command in Command::bot_commands() {
+            let key = format!("help-{}", command.command.trim_start_matches('/'));
+            assert!(english.contains_key(&key), "no description of {key}");
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            format(Language::En, "no-group", &[("group", &"devs")]),
+            "There is no group `devs`."
+        );
+        // Values are not formatted themselves.
+        assert_eq!(
+            format(
+                Language::En,
+                "joined-group",
+                &[("name", &"{group}"), ("group", &"devs")]
+            ),
+            "{group} joined `devs`."
+        );
+        assert_eq!(text(Language::Ru, "poll-yes"), "Да.");
+        assert_eq!(text(Language::Ja, "no-such-message"), "no-such-message");
+    }
+}
@@ -1,26 +1,42 @@
 #![forbid(unsafe_code)]
 
-use clap::Parser;
 use simplelog::*;
+use std::error::Error;
 use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
+use teloxide::adaptors::throttle::Limits;
+use teloxide::dispatching::{DefaultKey, DispatcherBuilder, UpdateHandler};
 use teloxide::prelude::*;
+use teloxide::update_listeners::webhooks;
+use tokio::task::JoinSet;
 
-use crate::commands::{endpoints, Command};
-use crate::storage::ChatStorage;
+use crate::cli::Backend;
+use crate::commands::{endpoints, Command, Cooldowns, PendingVotes};
+use crate::report::ErrorReporter;
+use crate::storage::{ChatStorage, SqliteStorage, Storage};
 
+mod api;
 mod cli;
 mod commands;
+mod config;
+mod i18n;
+mod mentions;
+mod report;
+mod settings;
 mod storage;
+mod webhook;
+
+#[cfg(test)]
+mod e2e;
 
 #[tokio::main]
 async fn main() {
-    let args = cli::Args::parse();
+    let args = cli::Args::load();
 
     TermLogger::init(
-        LevelFilter::Info,
+        args.log_level,
         ConfigBuilder::default()
             .add_filter_allow("minasan".to_string())
             .build(),
@@ -29,59 +45,325 @@ async fn main() {
     )
     .expect("TermLogger has already been created");
 
-    run(args.path, args.interval).await;
+    if args.check_config {
+        check_config(&args);
+        return;
+    }
+    run(args).await;
+}
+
+/// Prints the options in effect, leaving out the secrets, exits on errors.
+fn check_config(args: &cli::Args) {
+    if let Err(err) = args.read_token() {
+        eprintln!("error: {err}");
+        std::process::exit(1);
+    }
+    println!(
+        "Storage: {:?} at {}",
+        args.backend,
+        args.path.as_deref().unwrap_or("(none)")
+    );
+    match &args.webhook_url {
+        Some(url) => println!("Updates: webhook at {url}, listening on {}", args.listen),
+        None => println!("Updates: long polling"),
+    }
+    println!("Default chat settings: {:?}", args.chat);
+    println!("Configuration is valid.");
 }
 
-pub async fn run(path: Option<String>, interval: u16) {
-    let bot = Bot::from_env();
-    let chat_storage = Arc::new(match path {
-        Some(ref p) => {
-            log::info!("ChatStorage is loaded from {p}");
-            log::info!("Disk dump will happen after {interval} seconds");
-            ChatStorage::load(Path::new(p))
+pub async fn run(args: cli::Args) {
+    let token = args.read_token().unwrap_or_else(|err| {
+        log::error!("{err}");
+        std::process::exit(1);
+    });
+    let cli::Args {
+        backend,
+        path,
+        interval,
+        create_path,
+        shutdown_timeout,
+        webhook_url,
+        listen,
+        webhook_secret,
+        api_url,
+        owner_chat_ids,
+        chat,
+        ..
+    } = args;
+
+    let mut bot = Bot::with_client(token, teloxide::net::client_from_env());
+    if let Some(api_url) = api_url {
+        log::info!("Using Bot API at {api_url}");
+        bot = bot.set_api_url(api_url);
+    }
+    let bot = bot.throttle(Limits::default());
+
+    let (chat_storage, json_storage): (Arc<dyn Storage>, _) = match backend {
+        Backend::Json => {
+            let chat_storage = Arc::new(match path {
+                Some(ref p) => {
+                    log::info!("ChatStorage is loaded from {p}");
+                    log::info!(
+                        "Disk dump will happen {} after changes",
+                        humantime::format_duration(interval)
+                    );
+                    ChatStorage::load(Path::new(p), create_path)
+                        .unwrap_or_else(|err| {
+                            log::error!("Failed to load ChatStorage: {err}");
+                            std::process::exit(1);
+                        })
+                        .with_defaults(chat)
+                }
+                None => {
+                    log::info!("ChatStorage created anew.");
+                    ChatStorage::new().with_defaults(chat)
+                }
+            });
+            let dumper = spawn_dumper(Arc::clone(&chat_storage), path.clone(), interval);
+            (chat_storage.clone(), Some((chat_storage, dumper)))
+        }
+        Backend::Sqlite => {
+            let p = path
+                .clone()
+                .expect("clap requires path for the sqlite backend");
+            log::info!("SqliteStorage is opened at {p}");
+            if let Some(parent) = Path::new(&p).parent().filter(|_| create_path) {
+                if let Err(err) = std::fs::create_dir_all(parent) {
+                    log::error!("Failed to create {}: {err}", parent.display());
+                    std::process::exit(1);
+                }
+            }
+            let chat_storage = SqliteStorage::open(Path::new(&p)).unwrap_or_else(|err| {
+                log::error!("Failed to open {p}: {err}");
+                std::process::exit(1);
+            });
+            (Arc::new(chat_storage.with_defaults(chat)), None)
         }
-        None => {
-            log::info!("ChatStorage created anew.");
-            ChatStorage::new()
+    };
+
+    let owners = owner_chat_ids.into_iter().map(ChatId).collect();
+    let mut dispatcher = dispatcher(bot.clone(), chat_storage, owners).build();
+
+    let shutdown_token = dispatcher.shutdown_token();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        if shutdown_token.shutdown().is_err() {
+            log::info!("Stopped before dispatching started.");
+            std::process::exit(0);
         }
+        // Handlers and the final dump have `shutdown_timeout` to finish.
+        tokio::time::sleep(shutdown_timeout).await;
+        log::error!(
+            "Graceful shutdown took longer than {}, exiting.",
+            humantime::format_duration(shutdown_timeout)
+        );
+        std::process::exit(1);
     });
 
-    let handler = dptree::entry()
+    match webhook_url {
+        Some(url) => {
+            log::info!("Receiving updates at {url}, listening on {listen}.");
+            let mut options = webhooks::Options::new(listen, url);
+            options.secret_token = webhook_secret;
+            let listener = webhook::listener(bot, options).await.unwrap_or_else(|err| {
+                log::error!("Failed to set up the webhook: {err}");
+                std::process::exit(1);
+            });
+            let error_handler =
+                LoggingErrorHandler::with_custom_text("An error from the webhook listener");
+            dispatcher
+                .dispatch_with_listener(listener, error_handler)
+                .await;
+        }
+        None => dispatcher.dispatch().await,
+    }
+
+    if let Some((storage, dumper)) = json_storage {
+        dumper.abort();
+
+        match path {
+            Some(ref p) => match storage.dump(Path::new(p)).await {
+                Ok(count) => log::info!("Dumped database ({count} entries) to {p} on shutdown."),
+                Err(err) => log::error!("Database dump on shutdown failed: {err}."),
+            },
+            None => log::warn!("No db dump happened since no path was specified."),
+        }
+    }
+}
+
+/// Sets up the handling of updates, the same whether they are polled or
+/// come to the webhook.
+fn dispatcher(
+    bot: api::Bot,
+    chat_storage: Arc<dyn Storage>,
+    owners: Vec<ChatId>,
+) -> DispatcherBuilder<api::Bot, Box<dyn Error + Send + Sync>, DefaultKey> {
+    Dispatcher::builder(bot.clone(), schema())
+        .dependencies(dptree::deps![
+            chat_storage,
+            Arc::new(PendingVotes::default()),
+            Arc::new(Cooldowns::default())
+        ])
+        .error_handler(ErrorReporter::new(bot, owners))
+}
+
+/// Routes every kind of update the bot handles to its endpoint.
+fn schema() -> UpdateHandler<Box<dyn Error + Send + Sync>> {
+    dptree::entry()
+        .chain(report::attach_update())
         .branch(
-            Update::filter_message().chain(
-                teloxide::filter_command::<Command, _>()
-                    .branch(dptree::case![Command::MinasanStart].endpoint(endpoints::start))
-                    .branch(dptree::case![Command::MinasanRestart].endpoint(endpoints::restart))
-                    .branch(dptree::case![Command::MinasanPoll].endpoint(endpoints::get_poll))
-                    .branch(dptree::case![Command::MinasanKill].endpoint(endpoints::kill))
-                    .branch(dptree::case![Command::Minasan].endpoint(endpoints::tag_everyone))
-                    .branch(dptree::case![Command::MinasanHelp].endpoint(endpoints::help)),
-            ),
+            Update::filter_message()
+                .branch(
+                    dptree::filter_map(|message: Message| message.left_chat_member().cloned())
+                        .endpoint(endpoints::left_chat_member),
+                )
+                .branch(
+                    dptree::filter(|message: Message| {
+                        message.migrate_to_chat_id().is_some()
+                            || message.migrate_from_chat_id().is_some()
+                    })
+                    .endpoint(endpoints::migrate),
+                )
+                .branch(
+                    teloxide::filter_command::<Command, _>()
+                        .map_async(endpoints::message_language)
+                        .inspect_async(endpoints::warn_lost_poll)
+                        .inspect_async(endpoints::delete_command)
+                        .branch(
+                            dptree::filter_async(endpoints::admin_required)
+                                .endpoint(endpoints::deny),
+                        )
+                        .branch(dptree::case![Command::MinasanStart].endpoint(endpoints::start))
+                        .branch(dptree::case![Command::MinasanRestart].endpoint(endpoints::restart))
+                        .branch(dptree::case![Command::MinasanPoll].endpoint(endpoints::get_poll))
+                        .branch(dptree::case![Command::MinasanKill].endpoint(endpoints::kill))
+                        .branch(
+                            dptree::case![Command::Minasan(text)].endpoint(endpoints::tag_everyone),
+                        )
+                        .branch(dptree::case![Command::MinasanHelp].endpoint(endpoints::help))
+                        .branch(
+                            dptree::case![Command::MinasanGroups].endpoint(endpoints::list_groups),
+                        )
+                        .branch(
+                            dptree::case![Command::MinasanNewGroup(group)]
+                                .endpoint(endpoints::new_group),
+                        )
+                        .branch(
+                            dptree::case![Command::MinasanDelGroup(group)]
+                                .endpoint(endpoints::delete_group),
+                        )
+                        .branch(
+                            dptree::case![Command::MinasanJoin(group)].endpoint(endpoints::join),
+                        )
+                        .branch(
+                            dptree::case![Command::MinasanLeave(group)].endpoint(endpoints::leave),
+                        )
+                        .branch(
+                            dptree::case![Command::MinasanMode(mode)]
+                                .endpoint(endpoints::consent_mode),
+                        )
+                        .branch(
+                            dptree::case![Command::MinasanAdminOnly(commands)]
+                                .endpoint(endpoints::admin_only),
+                        )
+                        .branch(
+                            dptree::case![Command::MinasanSettings].endpoint(endpoints::settings),
+                        ),
+                ),
         )
-        .branch(Update::filter_poll_answer().endpoint(endpoints::update_users));
+        .branch(Update::filter_poll_answer().endpoint(endpoints::update_users))
+        .branch(
+            Update::filter_callback_query()
+                .map_async(endpoints::query_language)
+                .branch(
+                    dptree::filter(endpoints::is_settings_query)
+                        .endpoint(endpoints::update_settings),
+                )
+                .endpoint(endpoints::update_consent),
+        )
+        .branch(
+            Update::filter_my_chat_member()
+                .map_async(endpoints::member_language)
+                .endpoint(endpoints::update_membership),
+        )
+        .branch(Update::filter_chat_member().endpoint(endpoints::member_left))
+}
 
-    let storage = Arc::clone(&chat_storage);
+/// Resolves on Ctrl-C or `SIGTERM`, the latter is what `docker stop` sends.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    let mut dispatcher = Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![chat_storage])
-        .build();
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.expect("Failed to install Ctrl-C handler"),
+        () = terminate => {}
+    }
+    log::info!("Received shutdown signal.");
+}
 
-    let database_dumper = tokio::spawn(async move {
+/// Dumps the storage `interval` after the first change since the previous
+/// dump, so that the changes made meanwhile are written at once and an idle
+/// bot does not touch the disk. A failed dump
+/// is retried `interval` later, a panicked dumper is restarted.
+fn spawn_dumper(
+    storage: Arc<ChatStorage>,
+    path: Option<String>,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let Some(p) = path else {
+            log::warn!("No db dump will happen since no path was specified.");
+            return;
+        };
+        let mut pending = false;
         loop {
-            tokio::time::sleep(Duration::from_secs(interval as u64)).await;
-            if let Some(ref p) = path {
-                match storage.dump(Path::new(p)).await {
-                    Ok(count) => log::info!("Dumped database ({count} entries) to {p}."),
-                    Err(err) => log::warn!("Database dump failed: {err}."),
+            // Dropping the set, once the dumper is aborted, aborts the dumps too.
+            let mut dumps = JoinSet::new();
+            dumps.spawn(dump_changes(
+                Arc::clone(&storage),
+                p.clone(),
+                interval,
+                pending,
+            ));
+            match dumps.join_next().await {
+                Some(Err(err)) if err.is_panic() => {
+                    log::error!("Database dumper panicked, restarting it: {err}");
+                    // The changes of the panicked dump are still to be dumped.
+                    pending = true;
                 }
-            } else {
-                log::warn!("No db dump happened since no path was specified.");
+                _ => return,
             }
         }
-    });
+    })
+}
 
-    let result = tokio::join!(dispatcher.dispatch(), database_dumper);
-    if let Err(e) = result.1 {
-        panic!("{}", e);
+/// The loop of `spawn_dumper`, `pending` dumps without waiting for a change.
+async fn dump_changes(storage: Arc<ChatStorage>, p: String, interval: Duration, pending: bool) {
+    let mut failed = pending;
+    loop {
+        if !failed {
+            storage.changed().await;
+        }
+        tokio::time::sleep(interval).await;
+        failed = match storage.dump(Path::new(&p)).await {
+            Ok(count) => {
+                log::info!("Dumped database ({count} entries) to {p}.");
+                false
+            }
+            Err(err) => {
+                log::warn!(
+                    "Database dump failed, retrying in {}: {err}.",
+                    humantime::format_duration(interval)
+                );
+                true
+            }
+        };
     }
 }
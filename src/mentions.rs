@@ -0,0 +1,267 @@
+// Splitting of the mention list into messages Telegram will accept.
+
+use teloxide::types::{MessageEntity, MessageEntityKind, User, UserId};
+
+use crate::i18n;
+use crate::settings::Language;
+use crate::storage::Users;
+
+/// Telegram rejects messages longer than this (in UTF-16 code units).
+pub const MAX_MESSAGE_LENGTH: usize = 4096;
+/// Telegram only notifies the first ~50 mentions of a single message.
+pub const MAX_MENTIONS: usize = 50;
+
+/// Text of a single message along with its mention entities.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub text: String,
+    pub entities: Vec<MessageEntity>,
+}
+
+impl Chunk {
+    fn new() -> Self {
+        Self {
+            text: String::new(),
+            entities: Vec::new(),
+        }
+    }
+
+    // Telegram measures lengths and entity offsets in UTF-16 code units.
+    fn len(&self) -> usize {
+        self.text.encode_utf16().count()
+    }
+
+    // Mentions are separated by spaces and set apart from the header by an empty line.
+    fn separator(&self) -> &'static str {
+        match (self.text.is_empty(), self.entities.is_empty()) {
+            (true, _) => "",
+            (false, true) => "\n\n",
+            (false, false) => " ",
+        }
+    }
+
+    fn push(&mut self, mention: Mention, language: Language) {
+        self.text.push_str(self.separator());
+        let offset = self.len();
+        let text = mention.text(language);
+        let length = text.encode_utf16().count();
+        self.text.push_str(&text);
+
+        let entity = match mention {
+            Mention::User(id, name) => {
+                let user = User {
+                    id,
+                    is_bot: false,
+                    first_name: name,
+                    last_name: None,
+                    username: None,
+                    language_code: None,
+                    is_premium: false,
+                    added_to_attachment_menu: false,
+                };
+                MessageEntity::text_mention(user, offset, length)
+            }
+            Mention::Username(_) => MessageEntity::new(MessageEntityKind::Mention, offset, length),
+        };
+        self.entities.push(entity);
+    }
+}
+
+/// A member linked to by their id, or by the @username of one known only by it.
+enum Mention {
+    User(UserId, String),
+    Username(String),
+}
+
+impl Mention {
+    fn text(&self, language: Language) -> String {
+        match self {
+            Self::User(_, name) if name.trim().is_empty() => i18n::text(language, "anonymous"),
+            Self::User(_, name) => name.clone(),
+            Self::Username(username) => format!("@{username}"),
+        }
+    }
+}
+
+/// Builds space separated lists of display names, each one linked
+/// to its user with a `text_mention` entity, followed by the `usernames`,
+/// split into chunks holding at most `max_mentions` mentions and
+/// `max_length` characters. A non-empty `header` opens the first chunk,
+/// members without a name are called anonymous in `language`.
+pub fn split(
+    header: &str,
+    users: Users,
+    usernames: Vec<String>,
+    language: Language,
+    max_length: usize,
+    max_mentions: usize,
+) -> Vec<Chunk> {
+    let mut users = users.into_iter().collect::<Vec<_>>();
+    users.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+    let mentions = users
+        .into_iter()
+        .map(|(id, name)| Mention::User(id, name))
+        .chain(usernames.into_iter().map(Mention::Username));
+
+    let mut chunks = Vec::new();
+    let mut chunk = Chunk::new();
+    chunk.text.push_str(header.trim());
+
+    for mention in mentions {
+        let extra = mention.text(language).encode_utf16().count() + chunk.separator().len();
+        let full = chunk.entities.len() >= max_mentions || chunk.len() + extra > max_length;
+        if full && !chunk.text.is_empty() {
+            chunks.push(std::mem::replace(&mut chunk, Chunk::new()));
+        }
+        chunk.push(mention, language);
+    }
+
+    if !chunk.text.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+// Here on only are the tests for mention splitting.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn users(n: u64) -> Users {
+        (0..n).map(|i| (UserId(i), format!("user{i:03}"))).collect()
+    }
+
+    #[test]
+    fn test_split_mention_limit() {
+        let chunks = split(
+            "",
+            users(120),
+            vec![],
+            Language::En,
+            MAX_MESSAGE_LENGTH,
+            MAX_MENTIONS,
+        );
+
+        let sizes = chunks.iter().map(|c| c.entities.len()).collect::<Vec<_>>();
+        assert_eq!(sizes, vec![50, 50, 20]);
+        assert!(chunks[1].text.starts_with("user050 "));
+        assert_eq!(chunks[1].entities[0].offset, 0);
+    }
+
+    #[test]
+    fn test_split_length_limit() {
+        // Every mention is 7 characters long, plus a separating space.
+        let chunks = split("", users(10), vec![], Language::En, 7 * 3 + 2, MAX_MENTIONS);
+
+        let texts = chunks.iter().map(|c| c.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            vec![
+                "user000 user001 user002",
+                "user003 user004 user005",
+                "user006 user007 user008",
+                "user009",
+            ]
+        );
+        assert!(chunks.iter().all(|c| c.len() <= 7 * 3 + 2));
+    }
+
+    #[test]
+    fn test_split_utf16_offsets() {
+        let users = Users::from([
+            (UserId(1), String::from("🦀 Ferris")),
+            (UserId(2), String::from("皆さん")),
+        ]);
+        let chunks = split(
+            "",
+            users,
+            vec![],
+            Language::En,
+            MAX_MESSAGE_LENGTH,
+            MAX_MENTIONS,
+        );
+        assert_eq!(chunks.len(), 1);
+
+        let spans = chunks[0]
+            .entities
+            .iter()
+            .map(|e| match &e.kind {
+                MessageEntityKind::TextMention { user } => (user.id, e.offset, e.length),
+                kind => panic!("Unexpected entity {kind:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(spans, vec![(UserId(2), 0, 3), (UserId(1), 4, 9)]);
+    }
+
+    #[test]
+    fn test_split_empty() {
+        assert!(split(
+            "",
+            Users::new(),
+            vec![],
+            Language::En,
+            MAX_MESSAGE_LENGTH,
+            MAX_MENTIONS
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_split_header() {
+        let chunks = split(
+            " Standup! ",
+            users(3),
+            vec![],
+            Language::En,
+            MAX_MESSAGE_LENGTH,
+            2,
+        );
+
+        let texts = chunks.iter().map(|c| c.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(texts, vec!["Standup!\n\nuser000 user001", "user002"]);
+        assert_eq!(chunks[0].entities[0].offset, 10);
+
+        // The header gets a message of its own if no mention fits next to it.
+        let chunks = split("Standup!", users(2), vec![], Language::En, 12, MAX_MENTIONS);
+
+        let texts = chunks.iter().map(|c| c.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(texts, vec!["Standup!", "user000", "user001"]);
+        assert!(chunks[0].entities.is_empty());
+    }
+
+    #[test]
+    fn test_split_usernames() {
+        let chunks = split(
+            "",
+            users(1),
+            vec!["legacy".to_string()],
+            Language::En,
+            MAX_MESSAGE_LENGTH,
+            MAX_MENTIONS,
+        );
+
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].text, "user000 @legacy");
+        let entity = &chunks[0].entities[1];
+        assert_eq!(entity.kind, MessageEntityKind::Mention);
+        assert_eq!((entity.offset, entity.length), (8, 7));
+    }
+
+    #[test]
+    fn test_split_anonymous() {
+        let users = Users::from([(UserId(1), String::from(" "))]);
+        let chunks = split(
+            "",
+            users,
+            vec![],
+            Language::Ru,
+            MAX_MESSAGE_LENGTH,
+            MAX_MENTIONS,
+        );
+
+        assert_eq!(chunks[0].text, "аноним");
+        assert_eq!(chunks[0].entities[0].length, 6);
+    }
+}
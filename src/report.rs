@@ -0,0 +1,196 @@
+// Failed commands are answered in their chat instead of going silent,
+// and the details are sent to the owner of the bot, if one is configured.
+
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::ops::ControlFlow;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use teloxide::dispatching::{DpHandlerDescription, UpdateHandler};
+use teloxide::dptree::di::DependencySupplier;
+use teloxide::dptree::HandlerDescription;
+use teloxide::error_handlers::ErrorHandler;
+use teloxide::prelude::*;
+use teloxide::types::UpdateKind;
+
+use crate::api::{Bot, RequestExt};
+use crate::commands::endpoints::user_language;
+use crate::i18n;
+use crate::mentions::MAX_MESSAGE_LENGTH;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Error of an endpoint, with the update it failed to handle.
+#[derive(Debug)]
+pub struct UpdateError {
+    pub update: Arc<Update>,
+    pub error: BoxError,
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.update.chat() {
+            Some(chat) => write!(f, "update {} in chat # {}", self.update.id, chat.id)?,
+            None => write!(f, "update {}", self.update.id)?,
+        }
+        write!(f, ": {}", self.error)
+    }
+}
+
+impl Error for UpdateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
+/// Runs the rest of the schema, attaching the update to its errors.
+pub fn attach_update() -> UpdateHandler<BoxError> {
+    dptree::from_fn_with_description(
+        DpHandlerDescription::entry(),
+        |deps: DependencyMap, cont| async move {
+            let update: Arc<Update> = deps.get();
+            match cont(deps).await {
+                ControlFlow::Break(Err(error)) => {
+                    ControlFlow::Break(Err(Box::new(UpdateError { update, error }) as BoxError))
+                }
+                flow => flow,
+            }
+        },
+    )
+}
+
+/// Apologizes in the chat of a failed message and sends the details
+/// to every one of `owners`, logging them either way.
+pub struct ErrorReporter {
+    bot: Bot,
+    owners: Vec<ChatId>,
+}
+
+impl ErrorReporter {
+    pub fn new(bot: Bot, owners: Vec<ChatId>) -> Arc<Self> {
+        Arc::new(Self { bot, owners })
+    }
+
+    async fn report(&self, error: BoxError) {
+        let details = describe(&*error);
+        log::error!("{details}");
+
+        let update = error
+            .downcast_ref::<UpdateError>()
+            .map(|error| &error.update);
+        if let Some(UpdateKind::Message(message)) = update.map(|update| &update.kind) {
+            // The storage may be what failed, so the chat's choice is not looked up.
+            let language = message.from().and_then(user_language).unwrap_or_default();
+            let reply = self
+                .bot
+                .send_message(message.chat.id, i18n::text(language, "apology"))
+                .reply_to_message_id(message.id)
+                .allow_sending_without_reply(true)
+                .send_retrying()
+                .await;
+            if let Err(err) = reply {
+                log::warn!("Failed to apologize in chat # {}: {err}", message.chat.id);
+            }
+        }
+        self.notify_owners(&fit(&details)).await;
+    }
+
+    async fn notify_owners(&self, text: &str) {
+        for &owner in &self.owners {
+            if let Err(err) = self.bot.send_message(owner, text).send_retrying().await {
+                log::warn!("Failed to report an error to the owner chat # {owner}: {err}");
+            }
+        }
+    }
+}
+
+impl ErrorHandler<BoxError> for ErrorReporter {
+    fn handle_error(
+        self: Arc<Self>,
+        error: BoxError,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>> {
+        Box::pin(async move { self.report(error).await })
+    }
+}
+
+/// The error with its causes and the text of the failed message, if any.
+/// The text comes last, so that `fit` cuts it before the causes.
+fn describe(error: &(dyn Error + 'static)) -> String {
+    let Some(update_error) = error.downcast_ref::<UpdateError>() else {
+        return format!("Failed to handle an update: {error}");
+    };
+    let mut text = format!("Failed to handle {update_error}");
+    let mut source = update_error.error.source();
+    while let Some(cause) = source {
+        text += &format!("\nCaused by: {cause}");
+        source = cause.source();
+    }
+    if let UpdateKind::Message(message) = &update_error.update.kind {
+        if let Some(message_text) = message.text() {
+            text += &format!("\nMessage: {message_text}");
+        }
+    }
+    text
+}
+
+/// Cuts `text` with an ellipsis to what Telegram accepts in a message.
+fn fit(text: &str) -> String {
+    if text.encode_utf16().count() <= MAX_MESSAGE_LENGTH {
+        return text.to_string();
+    }
+    let mut length = '…'.len_utf16();
+    let mut fitted = text
+        .chars()
+        .take_while(|c| {
+            length += c.len_utf16();
+            length <= MAX_MESSAGE_LENGTH
+        })
+        .collect::<String>();
+    fitted.push('…');
+    fitted
+}
+
+// Here on only are the tests for error reports.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_long_message() {
+        let text = "🦀".repeat(MAX_MESSAGE_LENGTH);
+        // Parsed from text like the updates Telegram sends, `from_value` fails on them.
+        let update = json!({
+            "update_id": 1,
+            "message": {
+                "message_id": 2,
+                "date": 1_700_000_000,
+                "chat": {"id": -100, "type": "group", "title": "chat"},
+                "from": {"id": 3, "is_bot": false, "first_name": "user"},
+                "text": format!("/minasan {text}"),
+            },
+        });
+        let update = serde_json::from_str(&update.to_string()).unwrap();
+        let error: BoxError = Box::new(UpdateError {
+            update: Arc::new(update),
+            error: "disk is full".into(),
+        });
+
+        let report = fit(&describe(&*error));
+        // A crab takes two UTF-16 units, so one unit may be left.
+        assert!(report.encode_utf16().count() >= MAX_MESSAGE_LENGTH - 1);
+        assert!(report.encode_utf16().count() <= MAX_MESSAGE_LENGTH);
+        assert!(report.starts_with(
+            "Failed to handle update 1 in chat # -100: disk is full\nMessage: /minasan 🦀"
+        ));
+        assert!(report.ends_with("🦀…"));
+
+        // Short reports are sent as they are.
+        let error: BoxError = "boom".into();
+        assert_eq!(fit(&describe(&*error)), "Failed to handle an update: boom");
+    }
+}
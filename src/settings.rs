@@ -0,0 +1,109 @@
+// Per-chat settings, changed by the chat and kept next to its users.
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Commands only administrators may run in a chat that did not choose otherwise.
+pub const DEFAULT_ADMIN_COMMANDS: [&str; 3] = ["minasankill", "minasanmode", "minasanrestart"];
+
+/// How chat members consent to be tagged.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsentMode {
+    /// Voting in a non-anonymous poll.
+    #[default]
+    Poll,
+    /// Pressing "Tag me" / "Don't tag me" buttons under a message.
+    Buttons,
+}
+
+/// Language of the messages of the bot, see `i18n`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    #[default]
+    En,
+    Ru,
+    Ja,
+}
+
+impl Language {
+    pub const ALL: [Language; 3] = [Language::En, Language::Ru, Language::Ja];
+
+    /// Name of the language in itself.
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::En => "English",
+            Language::Ru => "Русский",
+            Language::Ja => "日本語",
+        }
+    }
+
+    /// The language of an IETF tag like `ru` or `en-US`, as Telegram reports
+    /// the language of a user, if the bot speaks it.
+    pub fn from_code(code: &str) -> Option<Self> {
+        let primary = code.split(['-', '_']).next().unwrap_or_default();
+        Self::ALL
+            .into_iter()
+            .find(|language| primary.eq_ignore_ascii_case(language.code()))
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Language::En => "en",
+            Language::Ru => "ru",
+            Language::Ja => "ja",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChatSettings {
+    /// Names of the commands (without `/`) only chat administrators may run.
+    pub admin_commands: BTreeSet<String>,
+    /// Used by the next `/minasanstart` or `/minasanrestart`.
+    pub consent: ConsentMode,
+    /// `None` answers everyone in the language of their Telegram client.
+    pub language: Option<Language>,
+    /// Minimum time between two `/minasan`, zero for none.
+    pub cooldown: Duration,
+    /// Whether the commands handled by the bot are deleted from the chat.
+    pub delete_commands: bool,
+}
+
+impl ChatSettings {
+    /// Defaults of the bot itself, used unless the configuration changes them.
+    pub fn builtin() -> Self {
+        Self {
+            admin_commands: DEFAULT_ADMIN_COMMANDS.map(String::from).into(),
+            consent: ConsentMode::default(),
+            language: None,
+            cooldown: Duration::ZERO,
+            delete_commands: false,
+        }
+    }
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+// Here on only are the tests for the settings.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_language_from_code() {
+        assert_eq!(Language::from_code("ru"), Some(Language::Ru));
+        assert_eq!(Language::from_code("en-US"), Some(Language::En));
+        assert_eq!(Language::from_code("JA"), Some(Language::Ja));
+        assert_eq!(Language::from_code("de"), None);
+        assert_eq!(Language::from_code(""), None);
+    }
+}
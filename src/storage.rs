@@ -1,25 +1,236 @@
-// Refactor all the loading and dumping (serde::{Serialize, Desirialize}
-// Remove poll2id
+// What the bot remembers about its chats: the `Storage` trait and its in-memory
+// implementation dumped to JSON files, SQLite being the other one.
 
 use serde_json::Value;
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex as StdMutex, PoisonError};
 
+use async_trait::async_trait;
 use teloxide::prelude::*;
-use teloxide::types::MessageId;
-use tokio::sync::Mutex;
+use teloxide::types::{MessageId, UserId};
+use tokio::sync::{Mutex, MutexGuard, Notify};
 
-type MessageStorage = HashMap<ChatId, MessageId>;
-type UserStorage = HashMap<ChatId, HashSet<String>>;
+pub use sqlite::SqliteStorage;
+
+use crate::settings::{ChatSettings, ConsentMode};
+use snapshot::{ChatSnapshot, ManifestSnapshot, SettingsSnapshot, UserSnapshot};
+
+mod snapshot;
+mod sqlite;
+
+/// Consented users of a chat: Telegram user id to display name.
+pub type Users = HashMap<UserId, String>;
+
+/// Named tag groups of a chat: group name to its members.
+pub type Groups = HashMap<String, Users>;
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sqlite(err) => write!(f, "SQLite storage error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::Sqlite(err)
+    }
+}
+
+/// Failure to load a JSON dump or one of its files.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The file or directory could not be read.
+    Io(PathBuf, io::Error),
+    /// The file is not valid JSON.
+    Json(PathBuf, serde_json::Error),
+    /// The file is valid JSON, but not a dump.
+    Format(PathBuf, String),
+}
+
+impl LoadError {
+    fn format(path: &Path, reason: impl Into<String>) -> Self {
+        Self::Format(path.to_path_buf(), reason.into())
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(path, err) => write!(f, "failed to read {}: {err}", path.display()),
+            Self::Json(path, err) => write!(f, "invalid JSON in {}: {err}", path.display()),
+            Self::Format(path, reason) => write!(f, "malformed {}: {reason}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(_, err) => Some(err),
+            Self::Json(_, err) => Some(err),
+            Self::Format(..) => None,
+        }
+    }
+}
+
+/// Everything the bot remembers about its chats.
+///
+/// A chat is known from `add_chat` until `remove_chat`, methods returning
+/// `None` mean the chat (or the group) is unknown.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Starts tracking the chat with its consent message `message_id`, asking
+    /// for consent the `consent` way, forgetting its previous users and groups.
+    async fn add_chat(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        consent: ConsentMode,
+    ) -> StorageResult<()>;
+
+    async fn remove_chat(&self, chat_id: ChatId) -> StorageResult<Option<()>>;
+
+    /// Moves everything known about chat `from` to chat `to`, replacing what was
+    /// known about `to`, once a group is upgraded to a supergroup with a new id.
+    async fn migrate_chat(&self, from: ChatId, to: ChatId) -> StorageResult<Option<()>>;
+
+    async fn add_user(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        name: String,
+    ) -> StorageResult<Option<()>>;
+
+    async fn remove_user(&self, chat_id: ChatId, user_id: UserId) -> StorageResult<Option<()>>;
+
+    /// Removes the user from the chat and every its group, e.g. once they left the chat.
+    /// Returns `false` if the user was in none of them.
+    async fn forget_user(&self, chat_id: ChatId, user_id: UserId) -> StorageResult<Option<bool>>;
+
+    async fn get_users(&self, chat_id: ChatId) -> StorageResult<Option<Users>>;
+
+    async fn clean_users(&self, chat_id: ChatId) -> StorageResult<Option<()>>;
+
+    /// Consented members known only by their @username, they come from JSON
+    /// dumps older than user ids and are tagged until they vote again.
+    async fn get_usernames(&self, _chat_id: ChatId) -> StorageResult<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Forgets the @username, e.g. once its member voted again.
+    async fn remove_username(&self, _chat_id: ChatId, _username: &str) -> StorageResult<()> {
+        Ok(())
+    }
+
+    /// Replaces the consent message of the chat, asking for consent the `consent` way.
+    async fn update_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        consent: ConsentMode,
+    ) -> StorageResult<()>;
+
+    async fn get_message_id(&self, chat_id: ChatId) -> StorageResult<Option<MessageId>>;
+
+    /// How the consent message of the chat asks for consent, which differs
+    /// from `ChatSettings::consent` until the chat restarts after changing it.
+    async fn get_consent(&self, chat_id: ChatId) -> StorageResult<Option<ConsentMode>>;
+
+    /// Replaces the active poll of the chat.
+    async fn update_poll(&self, chat_id: ChatId, poll_id: String) -> StorageResult<()>;
+
+    async fn poll2chat(&self, poll_id: &str) -> StorageResult<Option<ChatId>>;
+
+    /// Id of the active poll of the chat, if it is known.
+    async fn get_poll(&self, chat_id: ChatId) -> StorageResult<Option<String>>;
+
+    /// Returns `false` if the group already exists.
+    async fn add_group(&self, chat_id: ChatId, group: &str) -> StorageResult<Option<bool>>;
+
+    /// Returns `false` if there was no such group.
+    async fn remove_group(&self, chat_id: ChatId, group: &str) -> StorageResult<Option<bool>>;
+
+    async fn get_groups(&self, chat_id: ChatId) -> StorageResult<Option<Groups>>;
+
+    async fn get_group(&self, chat_id: ChatId, group: &str) -> StorageResult<Option<Users>>;
+
+    async fn add_group_user(
+        &self,
+        chat_id: ChatId,
+        group: &str,
+        user_id: UserId,
+        name: String,
+    ) -> StorageResult<Option<()>>;
+
+    async fn remove_group_user(
+        &self,
+        chat_id: ChatId,
+        group: &str,
+        user_id: UserId,
+    ) -> StorageResult<Option<()>>;
+
+    async fn get_settings(&self, chat_id: ChatId) -> StorageResult<Option<ChatSettings>>;
+
+    /// Settings of the chats that did not change them, including the ones not started yet.
+    fn defaults(&self) -> ChatSettings;
+
+    async fn update_settings(
+        &self,
+        chat_id: ChatId,
+        settings: ChatSettings,
+    ) -> StorageResult<Option<()>>;
+}
+
+/// The consent message of each chat and how it asks for consent.
+type MessageStorage = HashMap<ChatId, (MessageId, ConsentMode)>;
+type UserStorage = HashMap<ChatId, Users>;
 type PollStorage = HashMap<String, ChatId>;
+type GroupStorage = HashMap<ChatId, Groups>;
+/// Only the settings the chats changed, see `SettingsSnapshot`.
+type SettingsStorage = HashMap<ChatId, SettingsSnapshot>;
+type UsernameStorage = HashMap<ChatId, BTreeSet<String>>;
 
+/// The maps of a `ChatStorage`, locked together by `ChatStorage::lock_chats`.
+struct ChatGuards<'a> {
+    users: MutexGuard<'a, UserStorage>,
+    messages: MutexGuard<'a, MessageStorage>,
+    polls: MutexGuard<'a, PollStorage>,
+    groups: MutexGuard<'a, GroupStorage>,
+    settings: MutexGuard<'a, SettingsStorage>,
+    usernames: MutexGuard<'a, UsernameStorage>,
+}
+
+/// In-memory storage, dumped to JSON files after it changes.
 pub struct ChatStorage {
     users: Mutex<UserStorage>,
     polls: Mutex<PollStorage>,
     messages: Mutex<MessageStorage>,
+    groups: Mutex<GroupStorage>,
+    settings: Mutex<SettingsStorage>,
+    usernames: Mutex<UsernameStorage>,
+    /// Settings of the chats that did not change them.
+    defaults: ChatSettings,
+    /// Chats changed since they were last dumped.
+    dirty: StdMutex<HashSet<ChatId>>,
+    /// Held while a dump is written.
+    dumping: Arc<Mutex<()>>,
+    changed: Notify,
 }
 
 impl ChatStorage {
@@ -28,195 +239,943 @@ impl ChatStorage {
             users: Mutex::new(UserStorage::new()),
             polls: Mutex::new(PollStorage::new()),
             messages: Mutex::new(MessageStorage::new()),
+            groups: Mutex::new(GroupStorage::new()),
+            settings: Mutex::new(SettingsStorage::new()),
+            usernames: Mutex::new(UsernameStorage::new()),
+            defaults: ChatSettings::default(),
+            dirty: StdMutex::new(HashSet::new()),
+            dumping: Arc::new(Mutex::new(())),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Makes the chats follow `defaults` in the settings they did not change.
+    pub fn with_defaults(mut self, defaults: ChatSettings) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    /// Resolves once some chat has changed since the last call.
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    fn touch(&self, chat_id: ChatId) {
+        lock(&self.dirty).insert(chat_id);
+        self.changed.notify_one();
+    }
+
+    /// Locks every map of the storage, always in the same order, so that
+    /// changes spanning several of them are never seen half done.
+    async fn lock_chats(&self) -> ChatGuards<'_> {
+        ChatGuards {
+            users: self.users.lock().await,
+            messages: self.messages.lock().await,
+            polls: self.polls.lock().await,
+            groups: self.groups.lock().await,
+            settings: self.settings.lock().await,
+            usernames: self.usernames.lock().await,
+        }
+    }
+
+    fn touch_if<T>(&self, chat_id: ChatId, result: Option<T>) -> StorageResult<Option<T>> {
+        if result.is_some() {
+            self.touch(chat_id);
         }
+        Ok(result)
+    }
+}
+
+#[async_trait]
+impl Storage for ChatStorage {
+    async fn add_chat(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        consent: ConsentMode,
+    ) -> StorageResult<()> {
+        let mut chat = self.lock_chats().await;
+        chat.users.insert(chat_id, Users::new());
+        chat.messages.insert(chat_id, (message_id, consent));
+        chat.groups.insert(chat_id, Groups::new());
+        chat.settings.entry(chat_id).or_default();
+        chat.usernames.remove(&chat_id);
+        drop(chat);
+        self.touch(chat_id);
+        Ok(())
     }
 
-    pub async fn add_chat(&self, chat_id: ChatId, message_id: MessageId) {
-        self.users.lock().await.insert(chat_id, HashSet::new());
-        self.messages.lock().await.insert(chat_id, message_id);
+    async fn remove_chat(&self, chat_id: ChatId) -> StorageResult<Option<()>> {
+        let mut chat = self.lock_chats().await;
+        if chat.users.remove(&chat_id).is_none() {
+            return Ok(None);
+        }
+        chat.messages.remove(&chat_id);
+        chat.polls.retain(|_, v| *v != chat_id);
+        chat.groups.remove(&chat_id);
+        chat.settings.remove(&chat_id);
+        chat.usernames.remove(&chat_id);
+        drop(chat);
+        self.touch(chat_id);
+        Ok(Some(()))
     }
 
-    pub async fn add_user(&self, chat_id: ChatId, new_user: String) -> Option<()> {
+    async fn migrate_chat(&self, from: ChatId, to: ChatId) -> StorageResult<Option<()>> {
+        fn move_chat<V>(storage: &mut HashMap<ChatId, V>, from: ChatId, to: ChatId) {
+            match storage.remove(&from) {
+                Some(value) => storage.insert(to, value),
+                None => storage.remove(&to),
+            };
+        }
+
+        let mut chat = self.lock_chats().await;
+        if !chat.users.contains_key(&from) {
+            return Ok(None);
+        }
+        move_chat(&mut chat.users, from, to);
+        move_chat(&mut chat.messages, from, to);
+        move_chat(&mut chat.groups, from, to);
+        move_chat(&mut chat.settings, from, to);
+        move_chat(&mut chat.usernames, from, to);
+        chat.polls.retain(|_, v| *v != to);
+        for chat_id in chat.polls.values_mut().filter(|v| **v == from) {
+            *chat_id = to;
+        }
+        drop(chat);
+
+        // The next dump writes the chat under its new id and drops the old file.
+        self.touch(from);
+        self.touch(to);
+        Ok(Some(()))
+    }
+
+    async fn add_user(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        name: String,
+    ) -> StorageResult<Option<()>> {
         let mut users = self.users.lock().await;
-        users.get_mut(&chat_id)?.insert(new_user);
-        Some(())
+        let result = users.get_mut(&chat_id).map(|users| {
+            users.insert(user_id, name);
+        });
+        drop(users);
+        self.touch_if(chat_id, result)
     }
 
-    pub async fn remove_user(&self, chat_id: ChatId, user: String) -> Option<()> {
-        self.users.lock().await.get_mut(&chat_id)?.remove(&user);
-        Some(())
+    async fn remove_user(&self, chat_id: ChatId, user_id: UserId) -> StorageResult<Option<()>> {
+        let mut users = self.users.lock().await;
+        let result = users.get_mut(&chat_id).map(|users| {
+            users.remove(&user_id);
+        });
+        drop(users);
+        self.touch_if(chat_id, result)
     }
 
-    pub async fn update_message(&self, chat_id: ChatId, message_id: MessageId) {
-        self.messages.lock().await.insert(chat_id, message_id);
+    async fn forget_user(&self, chat_id: ChatId, user_id: UserId) -> StorageResult<Option<bool>> {
+        let mut chat = self.lock_chats().await;
+        let Some(users) = chat.users.get_mut(&chat_id) else {
+            return Ok(None);
+        };
+        let mut forgot = users.remove(&user_id).is_some();
+        if let Some(groups) = chat.groups.get_mut(&chat_id) {
+            for members in groups.values_mut() {
+                forgot |= members.remove(&user_id).is_some();
+            }
+        }
+        drop(chat);
+        if forgot {
+            self.touch(chat_id);
+        }
+        Ok(Some(forgot))
     }
 
-    pub async fn get_users(&self, chat_id: ChatId) -> Option<HashSet<String>> {
-        self.users.lock().await.get(&chat_id).cloned()
+    async fn get_users(&self, chat_id: ChatId) -> StorageResult<Option<Users>> {
+        Ok(self.users.lock().await.get(&chat_id).cloned())
     }
 
-    pub async fn get_message_id(&self, chat_id: ChatId) -> Option<MessageId> {
-        self.messages.lock().await.get(&chat_id).cloned()
+    async fn clean_users(&self, chat_id: ChatId) -> StorageResult<Option<()>> {
+        let mut users = self.users.lock().await;
+        let result = users.get_mut(&chat_id).map(|users| users.clear());
+        drop(users);
+        if result.is_some() {
+            self.usernames.lock().await.remove(&chat_id);
+        }
+        self.touch_if(chat_id, result)
     }
 
-    pub async fn clean_users(&self, chat_id: ChatId) -> Option<()> {
-        self.users.lock().await.get_mut(&chat_id)?.clear();
-        Some(())
+    async fn get_usernames(&self, chat_id: ChatId) -> StorageResult<Vec<String>> {
+        let usernames = self.usernames.lock().await;
+        Ok(usernames
+            .get(&chat_id)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect())
     }
 
-    pub async fn update_poll(&self, chat_id: ChatId, poll_id: String) {
-        self.polls.lock().await.insert(poll_id, chat_id);
+    async fn remove_username(&self, chat_id: ChatId, username: &str) -> StorageResult<()> {
+        let mut usernames = self.usernames.lock().await;
+        let Some(chat_usernames) = usernames.get_mut(&chat_id) else {
+            return Ok(());
+        };
+        if chat_usernames.remove(username) {
+            if chat_usernames.is_empty() {
+                usernames.remove(&chat_id);
+            }
+            drop(usernames);
+            self.touch(chat_id);
+        }
+        Ok(())
+    }
+
+    async fn update_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        consent: ConsentMode,
+    ) -> StorageResult<()> {
+        self.messages
+            .lock()
+            .await
+            .insert(chat_id, (message_id, consent));
+        self.touch(chat_id);
+        Ok(())
+    }
+
+    async fn get_message_id(&self, chat_id: ChatId) -> StorageResult<Option<MessageId>> {
+        Ok(self
+            .messages
+            .lock()
+            .await
+            .get(&chat_id)
+            .map(|(message_id, _)| *message_id))
+    }
+
+    async fn get_consent(&self, chat_id: ChatId) -> StorageResult<Option<ConsentMode>> {
+        Ok(self
+            .messages
+            .lock()
+            .await
+            .get(&chat_id)
+            .map(|(_, consent)| *consent))
+    }
+
+    async fn update_poll(&self, chat_id: ChatId, poll_id: String) -> StorageResult<()> {
+        let mut polls = self.polls.lock().await;
+        polls.retain(|_, v| *v != chat_id);
+        polls.insert(poll_id, chat_id);
+        drop(polls);
+        self.touch(chat_id);
+        Ok(())
+    }
+
+    async fn poll2chat(&self, poll_id: &str) -> StorageResult<Option<ChatId>> {
+        Ok(self.polls.lock().await.get(poll_id).cloned())
+    }
+
+    async fn get_poll(&self, chat_id: ChatId) -> StorageResult<Option<String>> {
+        let polls = self.polls.lock().await;
+        Ok(polls
+            .iter()
+            .find(|(_, v)| **v == chat_id)
+            .map(|(poll_id, _)| poll_id.clone()))
+    }
+
+    async fn add_group(&self, chat_id: ChatId, group: &str) -> StorageResult<Option<bool>> {
+        let mut groups = self.groups.lock().await;
+        let Some(chat_groups) = groups.get_mut(&chat_id) else {
+            return Ok(None);
+        };
+        if chat_groups.contains_key(group) {
+            return Ok(Some(false));
+        }
+        chat_groups.insert(group.to_string(), Users::new());
+        drop(groups);
+        self.touch(chat_id);
+        Ok(Some(true))
     }
 
-    pub async fn poll2chat(&self, poll_id: &String) -> Option<ChatId> {
-        self.polls.lock().await.get(poll_id).cloned()
+    async fn remove_group(&self, chat_id: ChatId, group: &str) -> StorageResult<Option<bool>> {
+        let mut groups = self.groups.lock().await;
+        let result = groups
+            .get_mut(&chat_id)
+            .map(|groups| groups.remove(group).is_some());
+        drop(groups);
+        self.touch_if(chat_id, result)
     }
 
-    pub async fn remove_chat(&self, chat_id: ChatId) -> Option<()> {
-        // TODO: should be under one lock (try rwlock)
-        self.users.lock().await.remove(&chat_id)?;
-        self.messages.lock().await.remove(&chat_id)?;
-        Some(())
+    async fn get_groups(&self, chat_id: ChatId) -> StorageResult<Option<Groups>> {
+        Ok(self.groups.lock().await.get(&chat_id).cloned())
     }
+
+    async fn get_group(&self, chat_id: ChatId, group: &str) -> StorageResult<Option<Users>> {
+        let groups = self.groups.lock().await;
+        Ok(groups.get(&chat_id).and_then(|g| g.get(group)).cloned())
+    }
+
+    async fn add_group_user(
+        &self,
+        chat_id: ChatId,
+        group: &str,
+        user_id: UserId,
+        name: String,
+    ) -> StorageResult<Option<()>> {
+        let mut groups = self.groups.lock().await;
+        let result = groups
+            .get_mut(&chat_id)
+            .and_then(|g| g.get_mut(group))
+            .map(|members| {
+                members.insert(user_id, name);
+            });
+        drop(groups);
+        self.touch_if(chat_id, result)
+    }
+
+    async fn remove_group_user(
+        &self,
+        chat_id: ChatId,
+        group: &str,
+        user_id: UserId,
+    ) -> StorageResult<Option<()>> {
+        let mut groups = self.groups.lock().await;
+        let result = groups
+            .get_mut(&chat_id)
+            .and_then(|g| g.get_mut(group))
+            .map(|members| {
+                members.remove(&user_id);
+            });
+        drop(groups);
+        self.touch_if(chat_id, result)
+    }
+
+    fn defaults(&self) -> ChatSettings {
+        self.defaults.clone()
+    }
+
+    async fn get_settings(&self, chat_id: ChatId) -> StorageResult<Option<ChatSettings>> {
+        let settings_storage = self.settings.lock().await;
+        Ok(settings_storage
+            .get(&chat_id)
+            .map(|changed| changed.clone().into_settings(self.defaults.clone())))
+    }
+
+    async fn update_settings(
+        &self,
+        chat_id: ChatId,
+        settings: ChatSettings,
+    ) -> StorageResult<Option<()>> {
+        let mut settings_storage = self.settings.lock().await;
+        let result = settings_storage.get_mut(&chat_id).map(|changed| {
+            let old = changed.clone().into_settings(self.defaults.clone());
+            changed.update(&old, &settings);
+        });
+        drop(settings_storage);
+        self.touch_if(chat_id, result)
+    }
+}
+
+fn users_to_snapshot(users: &Users) -> Vec<UserSnapshot> {
+    let mut users = users
+        .iter()
+        .map(|(id, name)| UserSnapshot {
+            id: id.0,
+            name: name.clone(),
+        })
+        .collect::<Vec<_>>();
+    users.sort_by_key(|user| user.id);
+    users
+}
+
+fn users_from_snapshot(users: Vec<UserSnapshot>) -> Users {
+    users
+        .into_iter()
+        .map(|user| (UserId(user.id), user.name))
+        .collect()
+}
+
+/// Name of the file listing the chat files of the latest complete dump.
+const MANIFEST: &str = "manifest.json";
+
+/// Returns `None` if there is no manifest in `path`.
+fn read_manifest(path: &Path) -> Result<Option<ManifestSnapshot>, LoadError> {
+    let path = path.join(MANIFEST);
+    let json = match read_json(&path) {
+        Err(LoadError::Io(_, err)) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        result => result?,
+    };
+    serde_json::from_value(json).map_err(|err| LoadError::format(&path, err.to_string()))
+}
+
+fn read_chat(path: &Path) -> Result<ChatSnapshot, LoadError> {
+    let json = read_json(path)?;
+    ChatSnapshot::from_json(json).map_err(|reason| LoadError::format(path, reason))
+}
+
+fn read_json(path: &Path) -> Result<Value, LoadError> {
+    let content = fs::read_to_string(path).map_err(|err| LoadError::Io(path.to_path_buf(), err))?;
+    serde_json::from_str(&content).map_err(|err| LoadError::Json(path.to_path_buf(), err))
+}
+
+/// Directory within the dump where unreadable files are moved to.
+const QUARANTINE: &str = "quarantine";
+
+/// Moves the file out of the way, so that it is neither loaded
+/// nor removed by the next dump, but is kept for inspection.
+fn quarantine(path: &Path, file: &Path) {
+    let Some(file_name) = file.file_name() else {
+        return;
+    };
+    let quarantine = path.join(QUARANTINE);
+    let result =
+        fs::create_dir_all(&quarantine).and_then(|()| fs::rename(file, quarantine.join(file_name)));
+    match result {
+        Ok(()) => log::warn!("Moved {} to {}", file.display(), quarantine.display()),
+        Err(err) => log::warn!("Failed to quarantine {}: {err}", file.display()),
+    }
+}
+
+/// Writes `contents` to a temporary file and renames it to `path` once it
+/// is on disk, so that after a crash `path` holds either old or new contents.
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Makes renames within the directory durable.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Whether the file was written by some dump: `<chat_id>.json` from before
+/// manifests, `<chat_id>.<generation>.json`, or the `<chat_id>.<generation>.tmp`
+/// and `manifest.tmp` left by `write_atomic`.
+fn is_dump_file(file_name: &str) -> bool {
+    if file_name == "manifest.tmp" {
+        return true;
+    }
+    let Some(stem) = file_name
+        .strip_suffix(".json")
+        .or_else(|| file_name.strip_suffix(".tmp"))
+    else {
+        return false;
+    };
+    let (chat_id, generation) = stem.split_once('.').unwrap_or((stem, "0"));
+    i64::from_str(chat_id).is_ok() && u64::from_str(generation).is_ok()
 }
 
 impl ChatStorage {
-    pub async fn dump(&self, path: &Path) -> std::io::Result<usize> {
-        let user_storage = self.users.lock().await;
-        // might have potential race condition here
-        let message_storage = self.messages.lock().await;
-        let poll2chat_ids = self.polls.lock().await;
-
-        let mut counter = 0;
-
-        for (chat_id, users) in user_storage.iter() {
-            let message_id = message_storage.get(chat_id).unwrap();
-            let poll_id = poll2chat_ids
-                .iter()
-                .find(|(_, v)| *v == chat_id)
-                .map_or("null", |(p, _)| p)
-                .to_string();
-
-            let json = serde_json::json!({
-                "message_id": message_id.0,
-                "poll_id": poll_id,
-                "users": users,
-            });
+    /// Dumps chats changed since the previous dump to a new generation of files
+    /// and then atomically replaces the manifest, so that either the whole dump
+    /// is visible or none of it. Unchanged chats keep their files.
+    ///
+    /// Returns the number of chat files written.
+    pub async fn dump(&self, path: &Path) -> io::Result<usize> {
+        // Concurrent dumps would write the same generation. The files are written
+        // on even if the dump is cancelled, so the writing holds the lock.
+        let dumping = Arc::clone(&self.dumping).lock_owned().await;
+        let previous = {
+            let path = path.to_path_buf();
+            blocking(move || Ok(read_previous_manifest(&path))).await?
+        };
+
+        let dirty = DirtyChats::take(&self.dirty);
+        let chats = self.snapshots(previous.as_ref(), &dirty.chats).await;
+        let path = path.to_path_buf();
+        let written = blocking(move || {
+            let _dumping = dumping;
+            write_dump(&path, previous, chats)
+        })
+        .await?;
+        dirty.dumped();
+        Ok(written)
+    }
 
-            let chat_id_str = chat_id.to_string();
-            let file_name = format!("{chat_id_str}.json");
+    /// Copies the chats to be written out of the storage: the changed ones and
+    /// the ones missing from the previous dump. The others are `None`.
+    async fn snapshots(
+        &self,
+        previous: Option<&ManifestSnapshot>,
+        dirty: &HashSet<ChatId>,
+    ) -> Vec<(ChatId, Option<ChatSnapshot>)> {
+        let ChatGuards {
+            users: user_storage,
+            messages: message_storage,
+            polls: poll2chat_ids,
+            groups: group_storage,
+            settings: settings_storage,
+            usernames: username_storage,
+        } = self.lock_chats().await;
 
-            let file = File::create(path.join(file_name))?;
-            let mut writer = BufWriter::new(file);
-            serde_json::to_writer(&mut writer, &json)?;
-            writer.flush()?;
-            counter += 1;
+        user_storage
+            .iter()
+            .map(|(chat_id, users)| {
+                let unchanged = !dirty.contains(chat_id)
+                    && previous.is_some_and(|m| m.chats.contains_key(&chat_id.0));
+                if unchanged {
+                    return (*chat_id, None);
+                }
+                let (message_id, consent) = message_storage[chat_id];
+                let snapshot = ChatSnapshot {
+                    version: snapshot::VERSION,
+                    message_id: message_id.0,
+                    consent,
+                    poll_id: poll2chat_ids
+                        .iter()
+                        .find(|(_, v)| *v == chat_id)
+                        .map(|(p, _)| p.clone()),
+                    users: users_to_snapshot(users),
+                    usernames: username_storage
+                        .get(chat_id)
+                        .into_iter()
+                        .flatten()
+                        .cloned()
+                        .collect(),
+                    groups: group_storage
+                        .get(chat_id)
+                        .into_iter()
+                        .flatten()
+                        .map(|(name, members)| (name.clone(), users_to_snapshot(members)))
+                        .collect(),
+                    settings: settings_storage.get(chat_id).cloned().unwrap_or_default(),
+                };
+                (*chat_id, Some(snapshot))
+            })
+            .collect()
+    }
+
+    /// Loads the dump referred to by the manifest, or every `.json` file
+    /// in `path` for dumps made before manifests were introduced.
+    ///
+    /// Chat files that cannot be loaded are skipped and quarantined,
+    /// a missing `path` is created if `create` is set.
+    pub fn load(path: &Path, create: bool) -> Result<Self, LoadError> {
+        if create && !path.exists() {
+            fs::create_dir_all(path).map_err(|err| LoadError::Io(path.to_path_buf(), err))?;
+            log::info!("Created storage directory {}", path.display());
+            return Ok(Self::new());
         }
 
-        Ok(counter)
-    }
-
-    pub fn load(path: &Path) -> Self {
-        let mut user_storage = UserStorage::new();
-        let mut message_storage = MessageStorage::new();
-        let mut poll2chat_id = PollStorage::new();
-
-        for p in path.read_dir().unwrap().flatten() {
-            if p.path().is_file() {
-                let content = std::fs::read_to_string(p.path()).unwrap();
-                let json =
-                    serde_json::from_str::<HashMap<String, Value>>(content.as_str()).unwrap();
-
-                let users = json
-                    .get("users")
-                    .unwrap()
-                    .as_array()
-                    .unwrap()
-                    .iter()
-                    .map(|v| v.as_str().unwrap().to_string())
-                    .collect::<HashSet<String>>();
-                let message_id =
-                    MessageId(json.get("message_id").unwrap().as_i64().unwrap() as i32);
-                let poll_id = json.get("poll_id").unwrap().as_str().unwrap().to_string();
-                let chat_id = ChatId(
-                    i64::from_str(p.path().as_path().file_stem().unwrap().to_str().unwrap())
-                        .unwrap(),
-                );
-
-                user_storage.insert(chat_id, users);
-                message_storage.insert(chat_id, message_id);
-                if poll_id != "null" {
-                    poll2chat_id.insert(poll_id, chat_id);
+        let chat_files = match read_manifest(path)? {
+            Some(manifest) => manifest
+                .chats
+                .into_iter()
+                .map(|(chat_id, file)| (Some(ChatId(chat_id)), path.join(file)))
+                .collect::<Vec<_>>(),
+            None => path
+                .read_dir()
+                .map_err(|err| LoadError::Io(path.to_path_buf(), err))?
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|p| p.is_file() && p.extension().is_some_and(|e| e == "json"))
+                .map(|p| {
+                    let chat_id = p
+                        .file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .and_then(|stem| i64::from_str(stem).ok())
+                        .map(ChatId);
+                    (chat_id, p)
+                })
+                .collect(),
+        };
+
+        let mut storage = Self::new();
+        let user_storage = storage.users.get_mut();
+        let message_storage = storage.messages.get_mut();
+        let poll2chat_id = storage.polls.get_mut();
+        let group_storage = storage.groups.get_mut();
+        let settings_storage = storage.settings.get_mut();
+        let username_storage = storage.usernames.get_mut();
+
+        for (chat_id, p) in chat_files {
+            let Some(chat_id) = chat_id else {
+                log::warn!("Skipping {}: file name is not a chat id", p.display());
+                quarantine(path, &p);
+                continue;
+            };
+            let chat = match read_chat(&p) {
+                Ok(chat) => chat,
+                Err(err) => {
+                    log::warn!("Skipping chat # {chat_id}: {err}");
+                    quarantine(path, &p);
+                    continue;
                 }
+            };
+
+            let groups = chat
+                .groups
+                .into_iter()
+                .map(|(name, members)| (name, users_from_snapshot(members)))
+                .collect();
+
+            user_storage.insert(chat_id, users_from_snapshot(chat.users));
+            message_storage.insert(chat_id, (MessageId(chat.message_id), chat.consent));
+            group_storage.insert(chat_id, groups);
+            settings_storage.insert(chat_id, chat.settings);
+            if !chat.usernames.is_empty() {
+                username_storage.insert(chat_id, chat.usernames.into_iter().collect());
+            }
+            if let Some(poll_id) = chat.poll_id {
+                poll2chat_id.insert(poll_id, chat_id);
             }
         }
 
-        Self {
-            users: Mutex::new(user_storage),
-            messages: Mutex::new(message_storage),
-            polls: Mutex::new(poll2chat_id),
+        Ok(storage)
+    }
+}
+
+/// Chats taken out of `ChatStorage::dirty` by a dump. They are put back
+/// unless the dump is done, so that a failed or cancelled dump is retried.
+struct DirtyChats<'a> {
+    dirty: &'a StdMutex<HashSet<ChatId>>,
+    chats: HashSet<ChatId>,
+}
+
+impl<'a> DirtyChats<'a> {
+    fn take(dirty: &'a StdMutex<HashSet<ChatId>>) -> Self {
+        let chats = std::mem::take(&mut *lock(dirty));
+        Self { dirty, chats }
+    }
+
+    fn dumped(mut self) {
+        self.chats.clear();
+    }
+}
+
+impl Drop for DirtyChats<'_> {
+    fn drop(&mut self) {
+        if !self.chats.is_empty() {
+            lock(self.dirty).extend(self.chats.drain());
         }
     }
 }
 
+fn lock<T>(mutex: &StdMutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Runs the file operations on a thread where blocking is fine.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
+
+fn read_previous_manifest(path: &Path) -> Option<ManifestSnapshot> {
+    match read_manifest(path) {
+        Ok(manifest) => manifest,
+        // The new manifest replaces the broken one anyway.
+        Err(err) => {
+            log::warn!("Dumping over a broken manifest: {err}");
+            None
+        }
+    }
+}
+
+/// Writes the new files of `chats` and the manifest listing them
+/// along with the files of the unchanged chats.
+fn write_dump(
+    path: &Path,
+    previous: Option<ManifestSnapshot>,
+    chats: Vec<(ChatId, Option<ChatSnapshot>)>,
+) -> io::Result<usize> {
+    let generation = previous.as_ref().map_or(0, |m| m.generation) + 1;
+    let mut manifest = ManifestSnapshot {
+        generation,
+        chats: BTreeMap::new(),
+    };
+
+    let mut written = 0;
+    for (chat_id, snapshot) in chats {
+        let file_name = match snapshot {
+            // Only chats listed in the previous manifest are left unchanged.
+            None => previous.as_ref().unwrap().chats[&chat_id.0].clone(),
+            Some(snapshot) => {
+                let file_name = format!("{chat_id}.{generation}.json");
+                write_atomic(&path.join(&file_name), &serde_json::to_vec(&snapshot)?)?;
+                written += 1;
+                file_name
+            }
+        };
+        manifest.chats.insert(chat_id.0, file_name);
+    }
+
+    if previous.is_some_and(|p| p.chats == manifest.chats) {
+        return Ok(0);
+    }
+
+    sync_dir(path)?;
+    write_atomic(&path.join(MANIFEST), &serde_json::to_vec(&manifest)?)?;
+    sync_dir(path)?;
+
+    // Files of previous dumps are garbage once the new manifest is in place.
+    for entry in path.read_dir()?.flatten() {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let referenced = manifest.chats.values().any(|f| *f == file_name);
+        if !referenced && is_dump_file(&file_name) {
+            if let Err(err) = fs::remove_file(entry.path()) {
+                log::warn!("Failed to remove stale dump file {file_name}: {err}");
+            }
+        }
+    }
+
+    Ok(written)
+}
+
 // Here on only are the tests for `ChatStorage`.
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::settings::Language;
     use pretty_assertions::assert_eq;
     use serde_json::json;
+    use std::io::BufWriter;
 
     #[tokio::test]
     async fn test_dump() {
         let chat_storage = ChatStorage::new();
 
         let chat_id = ChatId(0);
-        chat_storage.add_chat(chat_id, MessageId(1)).await;
         chat_storage
-            .add_user(chat_id, "user1".to_string())
+            .add_chat(chat_id, MessageId(1), ConsentMode::Poll)
             .await
             .unwrap();
         chat_storage
-            .add_user(chat_id, "user2".to_string())
+            .add_user(chat_id, UserId(1), "user1".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        chat_storage
+            .add_user(chat_id, UserId(2), "user2".to_string())
             .await
+            .unwrap()
             .unwrap();
 
         let chat_id = ChatId(123);
-        chat_storage.add_chat(chat_id, MessageId(321)).await;
         chat_storage
-            .add_user(chat_id, "user3".to_string())
+            .add_chat(chat_id, MessageId(321), ConsentMode::Poll)
+            .await
+            .unwrap();
+        chat_storage
+            .add_user(chat_id, UserId(3), "user3".to_string())
             .await
+            .unwrap()
             .unwrap();
         chat_storage
-            .add_user(chat_id, "user4".to_string())
+            .add_user(chat_id, UserId(4), "user4".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        chat_storage
+            .update_poll(chat_id, "12345".to_string())
             .await
             .unwrap();
-        chat_storage.update_poll(chat_id, "12345".to_string()).await;
 
         let tmp_dir = tempfile::tempdir().unwrap();
+        fs::write(tmp_dir.path().join("notes.tmp"), "not a dump").unwrap();
+        fs::write(tmp_dir.path().join("123.1.tmp"), "unfinished").unwrap();
         let n_dumped = chat_storage.dump(tmp_dir.path()).await.unwrap();
         assert_eq!(n_dumped, 2);
+        chat_storage
+            .remove_user(chat_id, UserId(4))
+            .await
+            .unwrap()
+            .unwrap();
+        let n_dumped = chat_storage.dump(tmp_dir.path()).await.unwrap();
+        assert_eq!(n_dumped, 1);
+
+        // Only the changed chat is rewritten, without any temporary files of
+        // dumps left, files of others stay.
+        let mut file_names = file_names(tmp_dir.path());
+        file_names.sort();
+        let target = vec![
+            "0.1.json".to_string(),
+            "123.2.json".to_string(),
+            MANIFEST.to_string(),
+            "notes.tmp".to_string(),
+        ];
+        assert_eq!(file_names, target);
+
+        let manifest = read_manifest(tmp_dir.path()).unwrap().unwrap();
+        assert_eq!(manifest.generation, 2);
+        assert_eq!(
+            manifest.chats,
+            BTreeMap::from([(0, "0.1.json".to_string()), (123, "123.2.json".to_string())])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dump_unchanged() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let chat_storage = ChatStorage::new();
+        chat_storage
+            .add_chat(ChatId(1), MessageId(1), ConsentMode::Poll)
+            .await
+            .unwrap();
+        assert_eq!(chat_storage.dump(tmp_dir.path()).await.unwrap(), 1);
+
+        // Lookups and mutations of missing chats change nothing.
+        chat_storage.get_users(ChatId(1)).await.unwrap();
+        chat_storage
+            .add_user(ChatId(2), UserId(1), "user1".to_string())
+            .await
+            .unwrap();
+        assert_eq!(chat_storage.dump(tmp_dir.path()).await.unwrap(), 0);
+        assert_eq!(
+            read_manifest(tmp_dir.path()).unwrap().unwrap().generation,
+            1
+        );
 
-        let mut file_names = tmp_dir
-            .path()
-            .read_dir()
+        // Loaded chats are not dumped again until they change.
+        let target = ChatStorage::load(tmp_dir.path(), false).unwrap();
+        assert_eq!(target.dump(tmp_dir.path()).await.unwrap(), 0);
+        target.remove_chat(ChatId(1)).await.unwrap();
+        assert_eq!(target.dump(tmp_dir.path()).await.unwrap(), 0);
+        let manifest = read_manifest(tmp_dir.path()).unwrap().unwrap();
+        assert_eq!(manifest.generation, 2);
+        assert!(manifest.chats.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dump_failure() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let chat_storage = ChatStorage::new();
+        chat_storage
+            .add_chat(ChatId(1), MessageId(1), ConsentMode::Poll)
+            .await
+            .unwrap();
+        chat_storage.dump(tmp_dir.path()).await.unwrap();
+
+        chat_storage
+            .add_user(ChatId(1), UserId(1), "user1".to_string())
+            .await
             .unwrap()
-            .flatten()
-            .map(|p| {
-                p.path()
-                    .as_path()
-                    .file_stem()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .to_string()
-            })
-            .collect::<Vec<_>>();
+            .unwrap();
+        // The temporary file of the chat cannot be created.
+        let blocker = tmp_dir.path().join("1.2.tmp");
+        fs::create_dir(&blocker).unwrap();
+        assert!(chat_storage.dump(tmp_dir.path()).await.is_err());
+
+        // The chat is still to be dumped.
+        fs::remove_dir(&blocker).unwrap();
+        assert_eq!(chat_storage.dump(tmp_dir.path()).await.unwrap(), 1);
+        let target = ChatStorage::load(tmp_dir.path(), false).unwrap();
+        assert_eq!(
+            target.get_users(ChatId(1)).await.unwrap().unwrap(),
+            Users::from([(UserId(1), "user1".to_string())])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_ignores_unfinished_dump() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+
+        let chat_storage = ChatStorage::new();
+        chat_storage
+            .add_chat(ChatId(1), MessageId(1), ConsentMode::Poll)
+            .await
+            .unwrap();
+        chat_storage.dump(tmp_dir.path()).await.unwrap();
+
+        // A dump that crashed before replacing the manifest.
+        fs::write(tmp_dir.path().join("1.2.json"), "{\"users\": [").unwrap();
+        fs::write(tmp_dir.path().join("2.2.tmp"), "{").unwrap();
+
+        let target = ChatStorage::load(tmp_dir.path(), false).unwrap();
+        assert_eq!(
+            target.get_message_id(ChatId(1)).await.unwrap(),
+            Some(MessageId(1))
+        );
+        assert!(target.get_users(ChatId(2)).await.unwrap().is_none());
+
+        // The next dump cleans the leftovers up.
+        target
+            .update_message(ChatId(1), MessageId(2), ConsentMode::Poll)
+            .await
+            .unwrap();
+        target.dump(tmp_dir.path()).await.unwrap();
+        let mut file_names = file_names(tmp_dir.path());
         file_names.sort();
-        let target = vec!["0".to_string(), "123".to_string()];
+        assert_eq!(
+            file_names,
+            vec!["1.2.json".to_string(), MANIFEST.to_string()]
+        );
+    }
 
-        assert_eq!(file_names, target);
+    #[tokio::test]
+    async fn test_load_skips_bad_files() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path();
+
+        fs::write(
+            path.join("1.json"),
+            json!({"users": [], "message_id": 5, "poll_id": "null"}).to_string(),
+        )
+        .unwrap();
+        fs::write(path.join(".DS_Store"), [0, 1, 2]).unwrap();
+        fs::write(path.join("2.json"), "{\"users\": [").unwrap();
+        fs::write(path.join("3.json"), json!({"users": []}).to_string()).unwrap();
+        fs::write(path.join("backup.json"), "{}").unwrap();
+
+        let chat_storage = ChatStorage::load(path, false).unwrap();
+        assert_eq!(
+            chat_storage.get_message_id(ChatId(1)).await.unwrap(),
+            Some(MessageId(5))
+        );
+        assert_eq!(chat_storage.users.lock().await.len(), 1);
+
+        let mut quarantined = file_names(&path.join(QUARANTINE));
+        quarantined.sort();
+        assert_eq!(quarantined, vec!["2.json", "3.json", "backup.json"]);
+        assert!(path.join(".DS_Store").exists());
+    }
+
+    #[test]
+    fn test_load_missing_dir() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("fresh").join("deploy");
+
+        assert!(matches!(
+            ChatStorage::load(&path, false),
+            Err(LoadError::Io(p, _)) if p == path
+        ));
+        ChatStorage::load(&path, true).unwrap();
+        assert!(path.is_dir());
+    }
+
+    #[test]
+    fn test_load_broken_manifest() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        fs::write(
+            tmp_dir.path().join(MANIFEST),
+            json!({"chats": {}}).to_string(),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            ChatStorage::load(tmp_dir.path(), false),
+            Err(LoadError::Format(..))
+        ));
+    }
+
+    fn file_names(path: &Path) -> Vec<String> {
+        path.read_dir()
+            .unwrap()
+            .flatten()
+            .map(|p| p.file_name().to_string_lossy().to_string())
+            .collect()
     }
 
     #[tokio::test]
@@ -224,7 +1183,7 @@ mod tests {
         let tmp_dir = tempfile::tempdir().unwrap();
 
         let json1 = json!({
-            "users": vec!["user1", "user2"],
+            "users": [{"id": 1, "name": "user1"}, {"id": 2, "name": "user2"}],
             "message_id": 123,
             "poll_id": "123456",
         });
@@ -235,7 +1194,7 @@ mod tests {
         writer.flush().unwrap();
 
         let json2 = json!({
-            "users": vec!["user3"],
+            "users": [{"id": 3, "name": "user3"}, "legacy_username"],
             "message_id": 890,
             "poll_id": "null",
         });
@@ -245,30 +1204,90 @@ mod tests {
         serde_json::to_writer(&mut writer, &json2).unwrap();
         writer.flush().unwrap();
 
-        let chat_storage = ChatStorage::load(tmp_dir.path());
+        let chat_storage = ChatStorage::load(tmp_dir.path(), false).unwrap();
 
         let (chat_id1, chat_id2) = (ChatId(1), ChatId(2));
         let (users1, users2) = (
-            HashSet::from(["user1".to_string(), "user2".to_string()]),
-            HashSet::from([String::from("user3")]),
+            Users::from([
+                (UserId(1), "user1".to_string()),
+                (UserId(2), "user2".to_string()),
+            ]),
+            Users::from([(UserId(3), String::from("user3"))]),
         );
         let (message_id1, message_id2) = (MessageId(123), MessageId(890));
         let (poll_id1, poll_id2) = (String::from("123456"), String::from("abc"));
 
-        assert_eq!(chat_storage.get_users(chat_id1).await.unwrap(), users1);
-        assert_eq!(chat_storage.get_users(chat_id2).await.unwrap(), users2);
+        assert_eq!(
+            chat_storage.get_users(chat_id1).await.unwrap().unwrap(),
+            users1
+        );
+        assert_eq!(
+            chat_storage.get_users(chat_id2).await.unwrap().unwrap(),
+            users2
+        );
 
         assert_eq!(
-            chat_storage.get_message_id(chat_id1).await.unwrap(),
+            chat_storage
+                .get_message_id(chat_id1)
+                .await
+                .unwrap()
+                .unwrap(),
             message_id1
         );
         assert_eq!(
-            chat_storage.get_message_id(chat_id2).await.unwrap(),
+            chat_storage
+                .get_message_id(chat_id2)
+                .await
+                .unwrap()
+                .unwrap(),
             message_id2
         );
+        // Unversioned chats could only consent with a poll.
+        assert_eq!(
+            chat_storage.get_consent(chat_id1).await.unwrap(),
+            Some(ConsentMode::Poll)
+        );
 
-        assert_eq!(chat_storage.poll2chat(&poll_id1).await.unwrap(), chat_id1);
-        assert!(chat_storage.poll2chat(&poll_id2).await.is_none());
+        assert_eq!(
+            chat_storage.poll2chat(&poll_id1).await.unwrap().unwrap(),
+            chat_id1
+        );
+        assert!(chat_storage.poll2chat(&poll_id2).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_load_usernames() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let json = json!({
+            "users": [{"id": 1, "name": "user1"}, "@legacy1", "legacy2"],
+            "message_id": 123,
+            "poll_id": "null",
+        });
+        fs::write(tmp_dir.path().join("1.json"), json.to_string()).unwrap();
+
+        let chat_id = ChatId(1);
+        let chat_storage = ChatStorage::load(tmp_dir.path(), false).unwrap();
+        assert_eq!(
+            chat_storage.get_usernames(chat_id).await.unwrap(),
+            ["legacy1", "legacy2"]
+        );
+
+        // Members who vote again are known by id from then on.
+        chat_storage
+            .remove_username(chat_id, "legacy1")
+            .await
+            .unwrap();
+        chat_storage.dump(tmp_dir.path()).await.unwrap();
+
+        let chat_storage = ChatStorage::load(tmp_dir.path(), false).unwrap();
+        assert_eq!(
+            chat_storage.get_usernames(chat_id).await.unwrap(),
+            ["legacy2"]
+        );
+        assert_eq!(
+            chat_storage.get_users(chat_id).await.unwrap().unwrap(),
+            Users::from([(UserId(1), "user1".to_string())])
+        );
     }
 
     #[tokio::test]
@@ -278,27 +1297,50 @@ mod tests {
         let source = ChatStorage::new();
 
         let chat_id = ChatId(101);
-        source.add_chat(chat_id, MessageId(1)).await;
         source
-            .add_user(chat_id, "usernamesome".to_string())
+            .add_chat(chat_id, MessageId(1), ConsentMode::Buttons)
+            .await
+            .unwrap();
+        source
+            .add_user(chat_id, UserId(5), "usernamesome".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        source
+            .add_user(chat_id, UserId(2), "user2".to_string())
             .await
+            .unwrap()
             .unwrap();
-        source.add_user(chat_id, "user2".to_string()).await.unwrap();
 
         let chat_id = ChatId(100);
-        source.add_chat(chat_id, MessageId(321)).await;
         source
-            .add_user(chat_id, "user3312".to_string())
+            .add_chat(chat_id, MessageId(321), ConsentMode::Poll)
+            .await
+            .unwrap();
+        source
+            .add_user(chat_id, UserId(6), "user3312".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        source
+            .add_user(chat_id, UserId(7), "someuser1234".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        source
+            .update_poll(chat_id, "12345".to_string())
             .await
             .unwrap();
+        source.add_group(chat_id, "backend").await.unwrap().unwrap();
+        source.add_group(chat_id, "oncall").await.unwrap().unwrap();
         source
-            .add_user(chat_id, "someuser1234".to_string())
+            .add_group_user(chat_id, "backend", UserId(6), "user3312".to_string())
             .await
+            .unwrap()
             .unwrap();
-        source.update_poll(chat_id, "12345".to_string()).await;
         source.dump(tempdir.path()).await.unwrap();
 
-        let target = ChatStorage::load(tempdir.path());
+        let target = ChatStorage::load(tempdir.path(), false).unwrap();
 
         assert_eq!(
             source.users.lock().await.clone(),
@@ -312,5 +1354,321 @@ mod tests {
             source.polls.lock().await.clone(),
             target.polls.lock().await.clone()
         );
+        assert_eq!(
+            source.groups.lock().await.clone(),
+            target.groups.lock().await.clone()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dump_concurrent_changes() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(ChatStorage::new());
+
+        let changes = {
+            let storage = Arc::clone(&storage);
+            tokio::spawn(async move {
+                for i in 0..2000 {
+                    storage
+                        .add_chat(ChatId(i), MessageId(1), ConsentMode::Poll)
+                        .await
+                        .unwrap();
+                    storage.migrate_chat(ChatId(i), ChatId(-i)).await.unwrap();
+                    if i % 2 == 0 {
+                        storage.remove_chat(ChatId(-i)).await.unwrap();
+                    }
+                }
+            })
+        };
+        // Snapshots see the chats either wholly added, moved or removed.
+        while !changes.is_finished() {
+            storage.snapshots(None, &HashSet::new()).await;
+        }
+        changes.await.unwrap();
+        storage.dump(tempdir.path()).await.unwrap();
+
+        let loaded = ChatStorage::load(tempdir.path(), false).unwrap();
+        assert_eq!(
+            loaded.messages.lock().await.clone(),
+            storage.messages.lock().await.clone()
+        );
+        assert_eq!(loaded.messages.lock().await.len(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_groups() {
+        let chat_storage = ChatStorage::new();
+        let chat_id = ChatId(7);
+
+        assert!(chat_storage
+            .add_group(chat_id, "backend")
+            .await
+            .unwrap()
+            .is_none());
+
+        chat_storage
+            .add_chat(chat_id, MessageId(1), ConsentMode::Poll)
+            .await
+            .unwrap();
+        assert_eq!(
+            chat_storage.add_group(chat_id, "backend").await.unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            chat_storage.add_group(chat_id, "backend").await.unwrap(),
+            Some(false)
+        );
+        assert!(chat_storage
+            .add_group_user(chat_id, "frontend", UserId(1), "user1".to_string())
+            .await
+            .unwrap()
+            .is_none());
+
+        chat_storage
+            .add_group_user(chat_id, "backend", UserId(1), "user1".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        chat_storage
+            .add_group_user(chat_id, "backend", UserId(2), "user2".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        chat_storage
+            .remove_group_user(chat_id, "backend", UserId(1))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            chat_storage
+                .get_group(chat_id, "backend")
+                .await
+                .unwrap()
+                .unwrap(),
+            Users::from([(UserId(2), "user2".to_string())])
+        );
+        // Group membership is independent of the chat-wide consent.
+        assert!(chat_storage
+            .get_users(chat_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(
+            chat_storage.remove_group(chat_id, "backend").await.unwrap(),
+            Some(true)
+        );
+        assert_eq!(
+            chat_storage.remove_group(chat_id, "backend").await.unwrap(),
+            Some(false)
+        );
+        assert!(chat_storage
+            .get_groups(chat_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_settings() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let chat_storage = ChatStorage::new();
+        let chat_id = ChatId(7);
+
+        let settings = ChatSettings {
+            admin_commands: ["minasan".to_string()].into(),
+            language: Some(Language::Ru),
+            cooldown: std::time::Duration::from_secs(300),
+            delete_commands: true,
+            ..Default::default()
+        };
+        assert!(chat_storage
+            .update_settings(chat_id, settings.clone())
+            .await
+            .unwrap()
+            .is_none());
+
+        chat_storage
+            .add_chat(chat_id, MessageId(1), ConsentMode::Poll)
+            .await
+            .unwrap();
+        assert_eq!(
+            chat_storage.get_settings(chat_id).await.unwrap(),
+            Some(ChatSettings::default())
+        );
+        chat_storage
+            .update_settings(chat_id, settings.clone())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Restarting the chat keeps its settings.
+        chat_storage
+            .add_chat(chat_id, MessageId(2), ConsentMode::Poll)
+            .await
+            .unwrap();
+        chat_storage.dump(tmp_dir.path()).await.unwrap();
+        let target = ChatStorage::load(tmp_dir.path(), false).unwrap();
+        assert_eq!(target.get_settings(chat_id).await.unwrap(), Some(settings));
+
+        target.remove_chat(chat_id).await.unwrap().unwrap();
+        assert!(target.get_settings(chat_id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_settings_follow_defaults() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let chat_storage = ChatStorage::new();
+        let chat_id = ChatId(7);
+        chat_storage
+            .add_chat(chat_id, MessageId(1), ConsentMode::Poll)
+            .await
+            .unwrap();
+        let settings = ChatSettings {
+            cooldown: std::time::Duration::from_secs(300),
+            ..Default::default()
+        };
+        chat_storage
+            .update_settings(chat_id, settings)
+            .await
+            .unwrap()
+            .unwrap();
+        chat_storage.dump(tmp_dir.path()).await.unwrap();
+
+        // Only the changed cooldown is kept, the rest follows the new defaults.
+        let defaults = ChatSettings {
+            language: Some(Language::Ja),
+            cooldown: std::time::Duration::from_secs(60),
+            delete_commands: true,
+            ..Default::default()
+        };
+        let target = ChatStorage::load(tmp_dir.path(), false)
+            .unwrap()
+            .with_defaults(defaults.clone());
+        assert_eq!(
+            target.get_settings(chat_id).await.unwrap(),
+            Some(ChatSettings {
+                cooldown: std::time::Duration::from_secs(300),
+                ..defaults
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forget_user() {
+        let chat_storage = ChatStorage::new();
+        let chat_id = ChatId(7);
+
+        assert!(chat_storage
+            .forget_user(chat_id, UserId(1))
+            .await
+            .unwrap()
+            .is_none());
+
+        chat_storage
+            .add_chat(chat_id, MessageId(1), ConsentMode::Poll)
+            .await
+            .unwrap();
+        for user_id in [UserId(1), UserId(2)] {
+            chat_storage
+                .add_user(chat_id, user_id, format!("user{user_id}"))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        for group in ["backend", "oncall"] {
+            chat_storage
+                .add_group(chat_id, group)
+                .await
+                .unwrap()
+                .unwrap();
+            chat_storage
+                .add_group_user(chat_id, group, UserId(1), "user1".to_string())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        assert!(chat_storage
+            .forget_user(chat_id, UserId(1))
+            .await
+            .unwrap()
+            .unwrap());
+        assert!(!chat_storage
+            .forget_user(chat_id, UserId(1))
+            .await
+            .unwrap()
+            .unwrap());
+        assert_eq!(
+            chat_storage.get_users(chat_id).await.unwrap().unwrap(),
+            Users::from([(UserId(2), "user2".to_string())])
+        );
+        let groups = chat_storage.get_groups(chat_id).await.unwrap().unwrap();
+        assert_eq!(groups.len(), 2);
+        assert!(groups.values().all(Users::is_empty));
+    }
+
+    #[tokio::test]
+    async fn test_migrate_chat() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let chat_storage = ChatStorage::new();
+        let (from, to) = (ChatId(-1), ChatId(-1001));
+
+        assert!(chat_storage.migrate_chat(from, to).await.unwrap().is_none());
+
+        chat_storage
+            .add_chat(from, MessageId(1), ConsentMode::Poll)
+            .await
+            .unwrap();
+        chat_storage
+            .add_user(from, UserId(1), "user1".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        chat_storage
+            .update_poll(from, "12345".to_string())
+            .await
+            .unwrap();
+        chat_storage
+            .add_group(from, "oncall")
+            .await
+            .unwrap()
+            .unwrap();
+        chat_storage.dump(tmp_dir.path()).await.unwrap();
+
+        chat_storage.migrate_chat(from, to).await.unwrap().unwrap();
+        assert!(chat_storage.get_users(from).await.unwrap().is_none());
+        assert!(chat_storage.get_settings(from).await.unwrap().is_none());
+        assert_eq!(
+            chat_storage.get_users(to).await.unwrap().unwrap(),
+            Users::from([(UserId(1), "user1".to_string())])
+        );
+        assert_eq!(
+            chat_storage.get_message_id(to).await.unwrap(),
+            Some(MessageId(1))
+        );
+        assert_eq!(chat_storage.poll2chat("12345").await.unwrap(), Some(to));
+        assert!(chat_storage.get_poll(from).await.unwrap().is_none());
+        assert_eq!(
+            chat_storage.get_poll(to).await.unwrap().as_deref(),
+            Some("12345")
+        );
+        assert!(chat_storage
+            .get_group(to, "oncall")
+            .await
+            .unwrap()
+            .is_some());
+
+        // The dumped file follows the chat.
+        chat_storage.dump(tmp_dir.path()).await.unwrap();
+        let mut file_names = file_names(tmp_dir.path());
+        file_names.sort();
+        assert_eq!(
+            file_names,
+            vec!["-1001.2.json".to_string(), MANIFEST.to_string()]
+        );
     }
 }
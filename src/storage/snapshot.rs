@@ -0,0 +1,296 @@
+// On-disk format of the JSON dump.
+//
+// Every file carries a `version`, older files are upgraded by `MIGRATIONS`
+// one version at a time before being deserialized. Whenever the format
+// changes, bump `VERSION` and append a migration from the previous one.
+
+use std::collections::BTreeMap;
+
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+use crate::settings::{ChatSettings, ConsentMode, Language};
+
+/// Version of the chat files written by this build.
+pub const VERSION: u64 = 1;
+
+/// `MIGRATIONS[n]` upgrades a version `n` chat file to version `n + 1`.
+const MIGRATIONS: [fn(Value) -> Result<Value, String>; VERSION as usize] = [v0_to_v1];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserSnapshot {
+    pub id: u64,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatSnapshot {
+    pub version: u64,
+    pub message_id: i32,
+    /// How the message asks for consent.
+    pub consent: ConsentMode,
+    pub poll_id: Option<String>,
+    pub users: Vec<UserSnapshot>,
+    /// Consented members known only by their @username,
+    /// from before users were tracked by id.
+    pub usernames: Vec<String>,
+    pub groups: BTreeMap<String, Vec<UserSnapshot>>,
+    pub settings: SettingsSnapshot,
+}
+
+/// Settings a chat changed itself, the missing ones follow the defaults
+/// of the bot and are filled by `into_settings`, so that changing the
+/// defaults changes them too and adding a setting does not need a
+/// migration. Also stored as is by `SqliteStorage`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SettingsSnapshot {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_commands: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consent: Option<ConsentMode>,
+    /// `Some(None)` follows the sender, unlike a missing language.
+    #[serde(deserialize_with = "present", skip_serializing_if = "Option::is_none")]
+    pub language: Option<Option<Language>>,
+    /// In seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cooldown: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delete_commands: Option<bool>,
+}
+
+/// A field given in the file, even as `null`.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+impl SettingsSnapshot {
+    /// The settings, taking the missing ones from `defaults`.
+    pub fn into_settings(self, defaults: ChatSettings) -> ChatSettings {
+        ChatSettings {
+            admin_commands: self
+                .admin_commands
+                .map_or(defaults.admin_commands, |commands| {
+                    commands.into_iter().collect()
+                }),
+            consent: self.consent.unwrap_or(defaults.consent),
+            language: self.language.unwrap_or(defaults.language),
+            cooldown: self.cooldown.map_or(defaults.cooldown, Duration::from_secs),
+            delete_commands: self.delete_commands.unwrap_or(defaults.delete_commands),
+        }
+    }
+
+    /// Keeps the settings changed from `old` to `new`, along with the ones
+    /// changed before.
+    pub fn update(&mut self, old: &ChatSettings, new: &ChatSettings) {
+        if new.admin_commands != old.admin_commands {
+            self.admin_commands = Some(new.admin_commands.iter().cloned().collect());
+        }
+        if new.consent != old.consent {
+            self.consent = Some(new.consent);
+        }
+        if new.language != old.language {
+            self.language = Some(new.language);
+        }
+        if new.cooldown != old.cooldown {
+            self.cooldown = Some(new.cooldown.as_secs());
+        }
+        if new.delete_commands != old.delete_commands {
+            self.delete_commands = Some(new.delete_commands);
+        }
+    }
+}
+
+impl ChatSnapshot {
+    /// Upgrades `json` of any known version and deserializes it.
+    pub fn from_json(mut json: Value) -> Result<Self, String> {
+        let version = match json.get("version") {
+            None => 0,
+            Some(version) => version.as_u64().ok_or("version is not a number")?,
+        };
+        if version > VERSION {
+            return Err(format!(
+                "version {version} is newer than supported {VERSION}"
+            ));
+        }
+
+        for migration in &MIGRATIONS[version as usize..] {
+            json = migration(json)?;
+        }
+        serde_json::from_value(json).map_err(|err| err.to_string())
+    }
+}
+
+/// Lists chat files of a complete dump.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestSnapshot {
+    pub generation: u64,
+    pub chats: BTreeMap<i64, String>,
+}
+
+/// Unversioned files: users are either bare @usernames (before users were
+/// tracked by id) or id and name objects, groups may be missing and a missing
+/// poll is the `"null"` string.
+///
+/// @usernames cannot be turned into ids, they are moved to `usernames`.
+/// Chats get default settings, which are all missing ones, and their message
+/// is a poll, the only way to consent back then.
+fn v0_to_v1(mut json: Value) -> Result<Value, String> {
+    let chat = json.as_object_mut().ok_or("chat is not an object")?;
+
+    /// Keeps the users with ids, returns the @usernames.
+    fn users(users: &mut Value) -> Result<Vec<Value>, String> {
+        let users = users.as_array_mut().ok_or("users are not an array")?;
+        let mut usernames = Vec::new();
+        users.retain(|user| {
+            if let Some(username) = user.as_str() {
+                usernames.push(Value::from(username.trim_start_matches('@')));
+                return false;
+            }
+            let has_id = user.get("id").is_some_and(Value::is_u64);
+            if !has_id {
+                log::warn!("Dropping user without id {user}");
+            }
+            has_id
+        });
+        for user in users {
+            let name = user.get("name").and_then(Value::as_str).unwrap_or_default();
+            user["name"] = Value::from(name);
+        }
+        Ok(usernames)
+    }
+
+    let usernames = users(chat.get_mut("users").ok_or("no users")?)?;
+    let groups = chat
+        .entry("groups")
+        .or_insert_with(|| Value::Object(Default::default()));
+    for members in groups
+        .as_object_mut()
+        .ok_or("groups are not an object")?
+        .values_mut()
+    {
+        // Groups came after ids, so they never had @usernames.
+        for username in users(members)? {
+            log::warn!("Dropping group member without id {username}");
+        }
+    }
+    chat.insert("usernames".to_string(), Value::from(usernames));
+    chat.insert("settings".to_string(), Value::Object(Default::default()));
+    chat.insert("consent".to_string(), Value::from("poll"));
+
+    if chat.get("poll_id").and_then(Value::as_str) == Some("null") {
+        chat.insert("poll_id".to_string(), Value::Null);
+    }
+    chat.insert("version".to_string(), Value::from(1));
+    Ok(json)
+}
+
+// Here on only are the tests for snapshot migrations.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_migrate_usernames() {
+        let json = json!({
+            "users": ["user1", "user2"],
+            "message_id": 123,
+            "poll_id": "null",
+        });
+
+        // Members who consented back then are still tagged.
+        let snapshot = ChatSnapshot::from_json(json).unwrap();
+        assert_eq!(
+            snapshot,
+            ChatSnapshot {
+                version: VERSION,
+                message_id: 123,
+                consent: ConsentMode::Poll,
+                poll_id: None,
+                users: vec![],
+                usernames: vec!["user1".to_string(), "user2".to_string()],
+                groups: BTreeMap::new(),
+                settings: SettingsSnapshot::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_migrate_unversioned_ids() {
+        let json = json!({
+            "users": [{"id": 1, "name": "user1"}, {"id": 2}],
+            "message_id": 5,
+            "poll_id": "12345",
+            "groups": {"oncall": [{"id": 1, "name": "user1"}, "user3"]},
+        });
+
+        let snapshot = ChatSnapshot::from_json(json).unwrap();
+        let user1 = UserSnapshot {
+            id: 1,
+            name: "user1".to_string(),
+        };
+        assert_eq!(
+            snapshot.users,
+            vec![
+                user1.clone(),
+                UserSnapshot {
+                    id: 2,
+                    name: String::new()
+                }
+            ]
+        );
+        assert_eq!(snapshot.poll_id.as_deref(), Some("12345"));
+        assert!(snapshot.usernames.is_empty());
+        assert_eq!(snapshot.groups["oncall"], vec![user1]);
+    }
+
+    #[test]
+    fn test_settings_defaults() {
+        let defaults = ChatSettings {
+            consent: ConsentMode::Buttons,
+            language: Some(Language::Ja),
+            ..ChatSettings::builtin()
+        };
+        let snapshot: SettingsSnapshot =
+            serde_json::from_value(json!({"language": null, "cooldown": 60})).unwrap();
+
+        // Only the missing settings are taken from the defaults.
+        let settings = snapshot.clone().into_settings(defaults.clone());
+        assert_eq!(settings.consent, ConsentMode::Buttons);
+        assert_eq!(settings.language, None);
+        assert_eq!(settings.cooldown, Duration::from_secs(60));
+
+        // Only the changed settings are written, the others keep following the defaults.
+        let mut changed = snapshot;
+        let new = ChatSettings {
+            delete_commands: true,
+            ..settings.clone()
+        };
+        changed.update(&settings, &new);
+        let written = serde_json::to_value(&changed).unwrap();
+        assert_eq!(
+            written,
+            json!({"language": null, "cooldown": 60, "delete_commands": true})
+        );
+        let snapshot: SettingsSnapshot = serde_json::from_value(written).unwrap();
+        assert_eq!(snapshot.clone().into_settings(defaults), new);
+        let settings = snapshot.into_settings(ChatSettings::builtin());
+        assert_eq!(settings.consent, ConsentMode::Poll);
+        assert!(settings.delete_commands);
+    }
+
+    #[test]
+    fn test_newer_version() {
+        let json = json!({"version": VERSION + 1});
+        assert!(ChatSnapshot::from_json(json).is_err());
+    }
+}
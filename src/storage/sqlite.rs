@@ -0,0 +1,906 @@
+// SQLite storage, every change is committed to disk right away.
+
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use async_trait::async_trait;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension, ToSql};
+use teloxide::prelude::*;
+use teloxide::types::{MessageId, UserId};
+
+use super::snapshot::SettingsSnapshot;
+use super::{Groups, Storage, StorageResult, Users};
+use crate::settings::{ChatSettings, ConsentMode};
+
+const SCHEMA: &str = "
+    PRAGMA foreign_keys = ON;
+
+    -- `consent` is how the message asks for it, `poll` or `buttons`.
+    CREATE TABLE IF NOT EXISTS chats (
+        chat_id    INTEGER PRIMARY KEY,
+        message_id INTEGER NOT NULL,
+        consent    TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS polls (
+        poll_id TEXT PRIMARY KEY,
+        chat_id INTEGER NOT NULL UNIQUE
+    );
+
+    CREATE TABLE IF NOT EXISTS users (
+        chat_id INTEGER NOT NULL REFERENCES chats (chat_id) ON DELETE CASCADE,
+        user_id INTEGER NOT NULL,
+        name    TEXT NOT NULL,
+        PRIMARY KEY (chat_id, user_id)
+    );
+
+    CREATE TABLE IF NOT EXISTS tag_groups (
+        chat_id INTEGER NOT NULL REFERENCES chats (chat_id) ON DELETE CASCADE,
+        name    TEXT NOT NULL,
+        PRIMARY KEY (chat_id, name)
+    );
+
+    CREATE TABLE IF NOT EXISTS tag_group_users (
+        chat_id    INTEGER NOT NULL,
+        group_name TEXT NOT NULL,
+        user_id    INTEGER NOT NULL,
+        name       TEXT NOT NULL,
+        PRIMARY KEY (chat_id, group_name, user_id),
+        FOREIGN KEY (chat_id, group_name)
+            REFERENCES tag_groups (chat_id, name) ON DELETE CASCADE
+    );
+
+    -- `SettingsSnapshot` JSON of the settings the chat changed,
+    -- so that new settings need no schema change.
+    CREATE TABLE IF NOT EXISTS chat_settings (
+        chat_id  INTEGER PRIMARY KEY REFERENCES chats (chat_id) ON DELETE CASCADE,
+        settings TEXT NOT NULL
+    );
+";
+
+pub struct SqliteStorage {
+    connection: Arc<Mutex<Connection>>,
+    /// Settings of the chats that did not change them.
+    defaults: ChatSettings,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it and its tables if needed.
+    pub fn open(path: &Path) -> StorageResult<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            defaults: ChatSettings::default(),
+        })
+    }
+
+    /// Makes the chats follow `defaults` in the settings they did not change.
+    pub fn with_defaults(mut self, defaults: ChatSettings) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    /// Runs the queries of `f` on a thread where blocking is fine,
+    /// so that waiting for the disk does not stall the handlers.
+    async fn with_connection<T, F>(&self, f: F) -> StorageResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = Arc::clone(&self.connection);
+        let result = tokio::task::spawn_blocking(move || {
+            // A panic in the middle of a transaction rolls it back.
+            let mut connection = connection.lock().unwrap_or_else(PoisonError::into_inner);
+            f(&mut connection)
+        })
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()));
+        Ok(result?)
+    }
+}
+
+impl ToSql for ConsentMode {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let consent = match self {
+            ConsentMode::Poll => "poll",
+            ConsentMode::Buttons => "buttons",
+        };
+        Ok(consent.into())
+    }
+}
+
+impl FromSql for ConsentMode {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "poll" => Ok(ConsentMode::Poll),
+            "buttons" => Ok(ConsentMode::Buttons),
+            other => Err(FromSqlError::Other(
+                format!("unknown consent mode {other}").into(),
+            )),
+        }
+    }
+}
+
+/// Settings the chat changed, `None` for an unknown chat.
+fn changed_settings(
+    connection: &Connection,
+    chat_id: ChatId,
+) -> rusqlite::Result<Option<SettingsSnapshot>> {
+    if !chat_exists(connection, chat_id)? {
+        return Ok(None);
+    }
+    let settings = connection
+        .query_row(
+            "SELECT settings FROM chat_settings WHERE chat_id = ?1",
+            params![chat_id.0],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+    let settings = settings.map_or_else(SettingsSnapshot::default, |json| {
+        serde_json::from_str(&json).unwrap_or_else(|err| {
+            log::warn!("Resetting malformed settings of chat # {chat_id}: {err}");
+            SettingsSnapshot::default()
+        })
+    });
+    Ok(Some(settings))
+}
+
+fn chat_exists(connection: &Connection, chat_id: ChatId) -> rusqlite::Result<bool> {
+    connection
+        .query_row(
+            "SELECT 1 FROM chats WHERE chat_id = ?1",
+            params![chat_id.0],
+            |_| Ok(()),
+        )
+        .optional()
+        .map(|row| row.is_some())
+}
+
+fn group_exists(connection: &Connection, chat_id: ChatId, group: &str) -> rusqlite::Result<bool> {
+    connection
+        .query_row(
+            "SELECT 1 FROM tag_groups WHERE chat_id = ?1 AND name = ?2",
+            params![chat_id.0, group],
+            |_| Ok(()),
+        )
+        .optional()
+        .map(|row| row.is_some())
+}
+
+fn query_users(
+    connection: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> rusqlite::Result<Users> {
+    let mut statement = connection.prepare(sql)?;
+    let users = statement
+        .query_map(params, |row| {
+            Ok((UserId(row.get::<_, i64>(0)? as u64), row.get(1)?))
+        })?
+        .collect();
+    users
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn add_chat(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        consent: ConsentMode,
+    ) -> StorageResult<()> {
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT INTO chats (chat_id, message_id, consent) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (chat_id) DO UPDATE
+                 SET message_id = excluded.message_id, consent = excluded.consent",
+                params![chat_id.0, message_id.0, consent],
+            )?;
+            transaction.execute("DELETE FROM users WHERE chat_id = ?1", params![chat_id.0])?;
+            transaction.execute(
+                "DELETE FROM tag_groups WHERE chat_id = ?1",
+                params![chat_id.0],
+            )?;
+            transaction.commit()
+        })
+        .await
+    }
+
+    async fn remove_chat(&self, chat_id: ChatId) -> StorageResult<Option<()>> {
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            let removed =
+                transaction.execute("DELETE FROM chats WHERE chat_id = ?1", params![chat_id.0])?;
+            transaction.execute("DELETE FROM polls WHERE chat_id = ?1", params![chat_id.0])?;
+            transaction.commit()?;
+            Ok((removed > 0).then_some(()))
+        })
+        .await
+    }
+
+    async fn migrate_chat(&self, from: ChatId, to: ChatId) -> StorageResult<Option<()>> {
+        self.with_connection(move |connection| {
+            if !chat_exists(connection, from)? {
+                return Ok(None);
+            }
+            let transaction = connection.transaction()?;
+            // Rows of the chat reference each other, they are consistent again by the commit.
+            transaction.execute_batch("PRAGMA defer_foreign_keys = ON")?;
+            transaction.execute("DELETE FROM chats WHERE chat_id = ?1", params![to.0])?;
+            transaction.execute("DELETE FROM polls WHERE chat_id = ?1", params![to.0])?;
+            for table in [
+                "chats",
+                "polls",
+                "users",
+                "tag_groups",
+                "tag_group_users",
+                "chat_settings",
+            ] {
+                transaction.execute(
+                    &format!("UPDATE {table} SET chat_id = ?1 WHERE chat_id = ?2"),
+                    params![to.0, from.0],
+                )?;
+            }
+            transaction.commit()?;
+            Ok(Some(()))
+        })
+        .await
+    }
+
+    async fn add_user(
+        &self,
+        chat_id: ChatId,
+        user_id: UserId,
+        name: String,
+    ) -> StorageResult<Option<()>> {
+        self.with_connection(move |connection| {
+            if !chat_exists(connection, chat_id)? {
+                return Ok(None);
+            }
+            connection.execute(
+                "INSERT OR REPLACE INTO users (chat_id, user_id, name) VALUES (?1, ?2, ?3)",
+                params![chat_id.0, user_id.0 as i64, name],
+            )?;
+            Ok(Some(()))
+        })
+        .await
+    }
+
+    async fn remove_user(&self, chat_id: ChatId, user_id: UserId) -> StorageResult<Option<()>> {
+        self.with_connection(move |connection| {
+            if !chat_exists(connection, chat_id)? {
+                return Ok(None);
+            }
+            connection.execute(
+                "DELETE FROM users WHERE chat_id = ?1 AND user_id = ?2",
+                params![chat_id.0, user_id.0 as i64],
+            )?;
+            Ok(Some(()))
+        })
+        .await
+    }
+
+    async fn forget_user(&self, chat_id: ChatId, user_id: UserId) -> StorageResult<Option<bool>> {
+        self.with_connection(move |connection| {
+            if !chat_exists(connection, chat_id)? {
+                return Ok(None);
+            }
+            let transaction = connection.transaction()?;
+            let users = transaction.execute(
+                "DELETE FROM users WHERE chat_id = ?1 AND user_id = ?2",
+                params![chat_id.0, user_id.0 as i64],
+            )?;
+            let members = transaction.execute(
+                "DELETE FROM tag_group_users WHERE chat_id = ?1 AND user_id = ?2",
+                params![chat_id.0, user_id.0 as i64],
+            )?;
+            transaction.commit()?;
+            Ok(Some(users + members > 0))
+        })
+        .await
+    }
+
+    async fn get_users(&self, chat_id: ChatId) -> StorageResult<Option<Users>> {
+        self.with_connection(move |connection| {
+            if !chat_exists(connection, chat_id)? {
+                return Ok(None);
+            }
+            let users = query_users(
+                connection,
+                "SELECT user_id, name FROM users WHERE chat_id = ?1",
+                params![chat_id.0],
+            )?;
+            Ok(Some(users))
+        })
+        .await
+    }
+
+    async fn clean_users(&self, chat_id: ChatId) -> StorageResult<Option<()>> {
+        self.with_connection(move |connection| {
+            if !chat_exists(connection, chat_id)? {
+                return Ok(None);
+            }
+            connection.execute("DELETE FROM users WHERE chat_id = ?1", params![chat_id.0])?;
+            Ok(Some(()))
+        })
+        .await
+    }
+
+    async fn update_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        consent: ConsentMode,
+    ) -> StorageResult<()> {
+        // Like `ChatStorage`, only `add_chat` starts tracking a chat.
+        self.with_connection(move |connection| {
+            connection.execute(
+                "UPDATE chats SET message_id = ?2, consent = ?3 WHERE chat_id = ?1",
+                params![chat_id.0, message_id.0, consent],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_message_id(&self, chat_id: ChatId) -> StorageResult<Option<MessageId>> {
+        self.with_connection(move |connection| {
+            connection
+                .query_row(
+                    "SELECT message_id FROM chats WHERE chat_id = ?1",
+                    params![chat_id.0],
+                    |row| row.get(0).map(MessageId),
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn get_consent(&self, chat_id: ChatId) -> StorageResult<Option<ConsentMode>> {
+        self.with_connection(move |connection| {
+            connection
+                .query_row(
+                    "SELECT consent FROM chats WHERE chat_id = ?1",
+                    params![chat_id.0],
+                    |row| row.get(0),
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn update_poll(&self, chat_id: ChatId, poll_id: String) -> StorageResult<()> {
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute("DELETE FROM polls WHERE chat_id = ?1", params![chat_id.0])?;
+            transaction.execute(
+                "INSERT OR REPLACE INTO polls (poll_id, chat_id) VALUES (?1, ?2)",
+                params![poll_id, chat_id.0],
+            )?;
+            transaction.commit()
+        })
+        .await
+    }
+
+    async fn poll2chat(&self, poll_id: &str) -> StorageResult<Option<ChatId>> {
+        let poll_id = poll_id.to_string();
+        self.with_connection(move |connection| {
+            connection
+                .query_row(
+                    "SELECT chat_id FROM polls WHERE poll_id = ?1",
+                    params![poll_id],
+                    |row| row.get(0).map(ChatId),
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn get_poll(&self, chat_id: ChatId) -> StorageResult<Option<String>> {
+        self.with_connection(move |connection| {
+            connection
+                .query_row(
+                    "SELECT poll_id FROM polls WHERE chat_id = ?1",
+                    params![chat_id.0],
+                    |row| row.get(0),
+                )
+                .optional()
+        })
+        .await
+    }
+
+    async fn add_group(&self, chat_id: ChatId, group: &str) -> StorageResult<Option<bool>> {
+        let group = group.to_string();
+        self.with_connection(move |connection| {
+            if !chat_exists(connection, chat_id)? {
+                return Ok(None);
+            }
+            let added = connection.execute(
+                "INSERT OR IGNORE INTO tag_groups (chat_id, name) VALUES (?1, ?2)",
+                params![chat_id.0, group],
+            )?;
+            Ok(Some(added > 0))
+        })
+        .await
+    }
+
+    async fn remove_group(&self, chat_id: ChatId, group: &str) -> StorageResult<Option<bool>> {
+        let group = group.to_string();
+        self.with_connection(move |connection| {
+            if !chat_exists(connection, chat_id)? {
+                return Ok(None);
+            }
+            let removed = connection.execute(
+                "DELETE FROM tag_groups WHERE chat_id = ?1 AND name = ?2",
+                params![chat_id.0, group],
+            )?;
+            Ok(Some(removed > 0))
+        })
+        .await
+    }
+
+    async fn get_groups(&self, chat_id: ChatId) -> StorageResult<Option<Groups>> {
+        self.with_connection(move |connection| {
+            if !chat_exists(connection, chat_id)? {
+                return Ok(None);
+            }
+
+            let mut statement =
+                connection.prepare("SELECT name FROM tag_groups WHERE chat_id = ?1")?;
+            let mut groups = statement
+                .query_map(params![chat_id.0], |row| row.get::<_, String>(0))?
+                .map(|name| name.map(|name| (name, Users::new())))
+                .collect::<rusqlite::Result<Groups>>()?;
+
+            let mut statement = connection.prepare(
+                "SELECT group_name, user_id, name FROM tag_group_users WHERE chat_id = ?1",
+            )?;
+            let members = statement.query_map(params![chat_id.0], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    UserId(row.get::<_, i64>(1)? as u64),
+                    row.get::<_, String>(2)?,
+                ))
+            })?;
+            for member in members {
+                let (group, user_id, name) = member?;
+                groups.entry(group).or_default().insert(user_id, name);
+            }
+            Ok(Some(groups))
+        })
+        .await
+    }
+
+    async fn get_group(&self, chat_id: ChatId, group: &str) -> StorageResult<Option<Users>> {
+        let group = group.to_string();
+        self.with_connection(move |connection| {
+            if !group_exists(connection, chat_id, &group)? {
+                return Ok(None);
+            }
+            let users = query_users(
+                connection,
+                "SELECT user_id, name FROM tag_group_users WHERE chat_id = ?1 AND group_name = ?2",
+                params![chat_id.0, group],
+            )?;
+            Ok(Some(users))
+        })
+        .await
+    }
+
+    async fn add_group_user(
+        &self,
+        chat_id: ChatId,
+        group: &str,
+        user_id: UserId,
+        name: String,
+    ) -> StorageResult<Option<()>> {
+        let group = group.to_string();
+        self.with_connection(move |connection| {
+            if !group_exists(connection, chat_id, &group)? {
+                return Ok(None);
+            }
+            connection.execute(
+                "INSERT OR REPLACE INTO tag_group_users (chat_id, group_name, user_id, name)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![chat_id.0, group, user_id.0 as i64, name],
+            )?;
+            Ok(Some(()))
+        })
+        .await
+    }
+
+    async fn remove_group_user(
+        &self,
+        chat_id: ChatId,
+        group: &str,
+        user_id: UserId,
+    ) -> StorageResult<Option<()>> {
+        let group = group.to_string();
+        self.with_connection(move |connection| {
+            if !group_exists(connection, chat_id, &group)? {
+                return Ok(None);
+            }
+            connection.execute(
+                "DELETE FROM tag_group_users
+                 WHERE chat_id = ?1 AND group_name = ?2 AND user_id = ?3",
+                params![chat_id.0, group, user_id.0 as i64],
+            )?;
+            Ok(Some(()))
+        })
+        .await
+    }
+
+    fn defaults(&self) -> ChatSettings {
+        self.defaults.clone()
+    }
+
+    async fn get_settings(&self, chat_id: ChatId) -> StorageResult<Option<ChatSettings>> {
+        let changed = self
+            .with_connection(move |connection| changed_settings(connection, chat_id))
+            .await?;
+        Ok(changed.map(|changed| changed.into_settings(self.defaults.clone())))
+    }
+
+    async fn update_settings(
+        &self,
+        chat_id: ChatId,
+        settings: ChatSettings,
+    ) -> StorageResult<Option<()>> {
+        let defaults = self.defaults.clone();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            let Some(mut changed) = changed_settings(&transaction, chat_id)? else {
+                return Ok(None);
+            };
+            changed.update(&changed.clone().into_settings(defaults), &settings);
+            let changed =
+                serde_json::to_string(&changed).expect("settings are always serializable");
+            transaction.execute(
+                "INSERT OR REPLACE INTO chat_settings (chat_id, settings) VALUES (?1, ?2)",
+                params![chat_id.0, changed],
+            )?;
+            transaction.commit()?;
+            Ok(Some(()))
+        })
+        .await
+    }
+}
+
+// Here on only are the tests for `SqliteStorage`.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Language;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn test_persistence() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("minasan.sqlite");
+        let chat_id = ChatId(-100);
+
+        {
+            let storage = SqliteStorage::open(&path).unwrap();
+            // Unknown chats are not started by a new message.
+            storage
+                .update_message(chat_id, MessageId(4), ConsentMode::Poll)
+                .await
+                .unwrap();
+            assert!(storage.get_message_id(chat_id).await.unwrap().is_none());
+            storage
+                .add_chat(chat_id, MessageId(4), ConsentMode::Poll)
+                .await
+                .unwrap();
+            storage
+                .update_message(chat_id, MessageId(5), ConsentMode::Buttons)
+                .await
+                .unwrap();
+            storage
+                .update_poll(chat_id, "poll".to_string())
+                .await
+                .unwrap();
+            storage
+                .add_user(chat_id, UserId(1), "user1".to_string())
+                .await
+                .unwrap()
+                .unwrap();
+            storage
+                .add_user(chat_id, UserId(2), "user2".to_string())
+                .await
+                .unwrap()
+                .unwrap();
+            storage.add_group(chat_id, "oncall").await.unwrap().unwrap();
+            storage
+                .add_group_user(chat_id, "oncall", UserId(2), "user2".to_string())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        let storage = SqliteStorage::open(&path).unwrap();
+        assert_eq!(
+            storage.get_users(chat_id).await.unwrap().unwrap(),
+            Users::from([
+                (UserId(1), "user1".to_string()),
+                (UserId(2), "user2".to_string()),
+            ])
+        );
+        assert_eq!(
+            storage.get_message_id(chat_id).await.unwrap(),
+            Some(MessageId(5))
+        );
+        assert_eq!(
+            storage.get_consent(chat_id).await.unwrap(),
+            Some(ConsentMode::Buttons)
+        );
+        assert_eq!(storage.poll2chat("poll").await.unwrap(), Some(chat_id));
+        assert_eq!(
+            storage.get_poll(chat_id).await.unwrap().as_deref(),
+            Some("poll")
+        );
+        assert_eq!(
+            storage.get_groups(chat_id).await.unwrap().unwrap(),
+            Groups::from([(
+                "oncall".to_string(),
+                Users::from([(UserId(2), "user2".to_string())])
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remove_chat() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let storage = SqliteStorage::open(&tmp_dir.path().join("minasan.sqlite")).unwrap();
+        let chat_id = ChatId(1);
+
+        assert!(storage.remove_chat(chat_id).await.unwrap().is_none());
+        assert!(storage
+            .add_user(chat_id, UserId(1), "user1".to_string())
+            .await
+            .unwrap()
+            .is_none());
+
+        storage
+            .add_chat(chat_id, MessageId(1), ConsentMode::Poll)
+            .await
+            .unwrap();
+        storage
+            .update_poll(chat_id, "old".to_string())
+            .await
+            .unwrap();
+        storage
+            .update_poll(chat_id, "new".to_string())
+            .await
+            .unwrap();
+        storage
+            .add_group(chat_id, "backend")
+            .await
+            .unwrap()
+            .unwrap();
+        storage
+            .add_group_user(chat_id, "backend", UserId(1), "user1".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(storage.poll2chat("old").await.unwrap().is_none());
+
+        storage.remove_chat(chat_id).await.unwrap().unwrap();
+        assert!(storage.get_message_id(chat_id).await.unwrap().is_none());
+        assert!(storage.poll2chat("new").await.unwrap().is_none());
+        assert!(storage.get_groups(chat_id).await.unwrap().is_none());
+
+        // Restarting the chat must not resurrect its old groups.
+        storage
+            .add_chat(chat_id, MessageId(2), ConsentMode::Poll)
+            .await
+            .unwrap();
+        assert!(storage
+            .get_groups(chat_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_settings() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("minasan.sqlite");
+        let chat_id = ChatId(1);
+        let settings = ChatSettings {
+            admin_commands: Default::default(),
+            language: Some(Language::Ja),
+            cooldown: std::time::Duration::from_secs(60),
+            ..Default::default()
+        };
+
+        {
+            let storage = SqliteStorage::open(&path).unwrap();
+            assert!(storage.get_settings(chat_id).await.unwrap().is_none());
+            storage
+                .add_chat(chat_id, MessageId(1), ConsentMode::Poll)
+                .await
+                .unwrap();
+            assert_eq!(
+                storage.get_settings(chat_id).await.unwrap(),
+                Some(ChatSettings::default())
+            );
+            storage
+                .update_settings(chat_id, settings.clone())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        let storage = SqliteStorage::open(&path).unwrap();
+        assert_eq!(storage.get_settings(chat_id).await.unwrap(), Some(settings));
+        storage.remove_chat(chat_id).await.unwrap().unwrap();
+        storage
+            .add_chat(chat_id, MessageId(2), ConsentMode::Poll)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get_settings(chat_id).await.unwrap(),
+            Some(ChatSettings::default())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_settings_follow_defaults() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("minasan.sqlite");
+        let chat_id = ChatId(1);
+
+        {
+            let storage = SqliteStorage::open(&path).unwrap();
+            storage
+                .add_chat(chat_id, MessageId(1), ConsentMode::Poll)
+                .await
+                .unwrap();
+            let settings = ChatSettings {
+                cooldown: std::time::Duration::from_secs(300),
+                ..Default::default()
+            };
+            storage
+                .update_settings(chat_id, settings)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        // Only the changed cooldown is kept, the rest follows the new defaults.
+        let defaults = ChatSettings {
+            language: Some(Language::Ja),
+            cooldown: std::time::Duration::from_secs(60),
+            delete_commands: true,
+            ..Default::default()
+        };
+        let storage = SqliteStorage::open(&path)
+            .unwrap()
+            .with_defaults(defaults.clone());
+        assert_eq!(
+            storage.get_settings(chat_id).await.unwrap(),
+            Some(ChatSettings {
+                cooldown: std::time::Duration::from_secs(300),
+                ..defaults
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forget_user() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let storage = SqliteStorage::open(&tmp_dir.path().join("minasan.sqlite")).unwrap();
+        let chat_id = ChatId(1);
+
+        assert!(storage
+            .forget_user(chat_id, UserId(1))
+            .await
+            .unwrap()
+            .is_none());
+
+        storage
+            .add_chat(chat_id, MessageId(1), ConsentMode::Poll)
+            .await
+            .unwrap();
+        storage
+            .add_user(chat_id, UserId(1), "user1".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        storage.add_group(chat_id, "oncall").await.unwrap().unwrap();
+        storage
+            .add_group_user(chat_id, "oncall", UserId(1), "user1".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(storage
+            .forget_user(chat_id, UserId(1))
+            .await
+            .unwrap()
+            .unwrap());
+        assert!(!storage
+            .forget_user(chat_id, UserId(1))
+            .await
+            .unwrap()
+            .unwrap());
+        assert!(storage
+            .get_users(chat_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_empty());
+        assert!(storage
+            .get_group(chat_id, "oncall")
+            .await
+            .unwrap()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_chat() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let storage = SqliteStorage::open(&tmp_dir.path().join("minasan.sqlite")).unwrap();
+        let (from, to) = (ChatId(-1), ChatId(-1001));
+
+        assert!(storage.migrate_chat(from, to).await.unwrap().is_none());
+
+        storage
+            .add_chat(from, MessageId(1), ConsentMode::Poll)
+            .await
+            .unwrap();
+        storage
+            .add_user(from, UserId(1), "user1".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        storage.update_poll(from, "poll".to_string()).await.unwrap();
+        storage.add_group(from, "oncall").await.unwrap().unwrap();
+        storage
+            .add_group_user(from, "oncall", UserId(1), "user1".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        storage
+            .update_settings(
+                from,
+                ChatSettings {
+                    admin_commands: Default::default(),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        // Some state of the new chat, e.g. from a `/minasanstart` there.
+        storage
+            .add_chat(to, MessageId(2), ConsentMode::Poll)
+            .await
+            .unwrap();
+
+        storage.migrate_chat(from, to).await.unwrap().unwrap();
+        assert!(storage.get_message_id(from).await.unwrap().is_none());
+        assert_eq!(
+            storage.get_message_id(to).await.unwrap(),
+            Some(MessageId(1))
+        );
+        assert_eq!(storage.poll2chat("poll").await.unwrap(), Some(to));
+        assert_eq!(
+            storage.get_group(to, "oncall").await.unwrap().unwrap(),
+            Users::from([(UserId(1), "user1".to_string())])
+        );
+        assert!(storage
+            .get_settings(to)
+            .await
+            .unwrap()
+            .unwrap()
+            .admin_commands
+            .is_empty());
+    }
+}
@@ -0,0 +1,55 @@
+// Receiving updates at a webhook. Telegram sends to a webhook only the kinds
+// of updates it was told about when the webhook was set, so the bot sets it
+// itself instead of leaving it to teloxide, which does not tell them.
+//
+// The webhook stays set when the bot stops: updates sent meanwhile wait for
+// the bot, which may be started by the very request that delivers them.
+
+use std::convert::Infallible;
+use std::error::Error;
+
+use teloxide::prelude::*;
+use teloxide::types::AllowedUpdate;
+use teloxide::update_listeners::{webhooks, UpdateListener};
+
+use crate::api::{Bot, RequestExt};
+
+/// Every kind of update `schema` handles. `chat_member` updates are only
+/// sent to bots asking for them.
+pub const ALLOWED_UPDATES: [AllowedUpdate; 5] = [
+    AllowedUpdate::Message,
+    AllowedUpdate::PollAnswer,
+    AllowedUpdate::CallbackQuery,
+    AllowedUpdate::MyChatMember,
+    AllowedUpdate::ChatMember,
+];
+
+/// Serves the webhook at the address of `options` and asks Telegram to send
+/// updates to its url.
+pub async fn listener(
+    bot: Bot,
+    mut options: webhooks::Options,
+) -> Result<impl UpdateListener<Err = Infallible>, Box<dyn Error + Send + Sync>> {
+    let server = axum::Server::try_bind(&options.address)?;
+    // Updates without the secret are rejected, one is made up unless given.
+    let secret = options.get_or_gen_secret_token().to_owned();
+    bot.set_webhook(options.url.clone())
+        .allowed_updates(ALLOWED_UPDATES)
+        .secret_token(secret)
+        .send_retrying()
+        .await?;
+
+    let (mut listener, stop_flag, router) = webhooks::axum_no_setup(options);
+    let stop_token = listener.stop_token();
+    tokio::spawn(async move {
+        let result = server
+            .serve(router.into_make_service())
+            .with_graceful_shutdown(stop_flag)
+            .await;
+        if let Err(err) = result {
+            log::error!("The webhook server failed: {err}");
+            stop_token.stop();
+        }
+    });
+    Ok(listener)
+}